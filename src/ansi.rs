@@ -0,0 +1,494 @@
+//! ANSI/VT100 escape sequence parsing.
+
+/// What a byte (or a run of them) of terminal output means.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Action {
+    /// A byte to display, bytes of multibyte characters are handed out one by one.
+    Print(u8),
+    /// A control character: C0 ones such as `LF`, `CR`, `BS` or `BEL`, and `DEL`.
+    Control(u8),
+    /// `ESC [ <private> <params> <intermediates> <final>`
+    Csi {
+        private: Option<u8>,
+        params: Vec<u16>,
+        final_byte: u8,
+    },
+    /// `ESC <intermediates> <final>`, such as `ESC 7` or `ESC ( B`.
+    Esc {
+        intermediates: Vec<u8>,
+        final_byte: u8,
+    },
+    /// `ESC ] ... BEL`, such as a window title.
+    Osc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum State {
+    #[default]
+    Ground,
+    Escape,
+    EscapeIntermediate,
+    Csi,
+    Osc,
+    OscEscape,
+    // DCS, SOS, PM and APC strings, dropped up to the string terminator.
+    String,
+    StringEscape,
+}
+
+const BEL: u8 = 0x07;
+const BS: u8 = 0x08;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+const ESC: u8 = 0x1b;
+const DEL: u8 = 0x7f;
+
+/// Incremental parser, sequences may be split across calls to `advance`.
+#[derive(Debug, Default)]
+pub(crate) struct Parser {
+    state: State,
+    private: Option<u8>,
+    params: Vec<u16>,
+    // Whether the current parameter got a digit yet, `ESC [ m` has no parameters at all.
+    param_started: bool,
+    intermediates: Vec<u8>,
+}
+
+impl Parser {
+    /// Feed one byte, returning what it completes, if anything.
+    pub(crate) fn advance(&mut self, byte: u8) -> Option<Action> {
+        // These abort any sequence.
+        if byte == CAN || byte == SUB {
+            self.state = State::Ground;
+            return Some(Action::Control(byte));
+        }
+
+        match self.state {
+            State::Ground => match byte {
+                ESC => {
+                    self.enter_escape();
+                    None
+                }
+                0..=0x1f | DEL => Some(Action::Control(byte)),
+                _ => Some(Action::Print(byte)),
+            },
+            State::Escape => self.escape(byte),
+            State::EscapeIntermediate => match byte {
+                ESC => {
+                    self.enter_escape();
+                    None
+                }
+                0x20..=0x2f => {
+                    self.intermediates.push(byte);
+                    None
+                }
+                0x30..=0x7e => {
+                    self.state = State::Ground;
+                    Some(Action::Esc {
+                        intermediates: std::mem::take(&mut self.intermediates),
+                        final_byte: byte,
+                    })
+                }
+                0..=0x1f => Some(Action::Control(byte)),
+                _ => None,
+            },
+            State::Csi => self.csi(byte),
+            State::Osc => match byte {
+                BEL => {
+                    self.state = State::Ground;
+                    Some(Action::Osc)
+                }
+                ESC => {
+                    self.state = State::OscEscape;
+                    None
+                }
+                _ => None,
+            },
+            State::OscEscape => {
+                if byte == b'\\' {
+                    self.state = State::Ground;
+                    Some(Action::Osc)
+                } else {
+                    // Unterminated, the escape starts a new sequence.
+                    self.enter_escape();
+                    self.escape(byte)
+                }
+            }
+            State::String => {
+                if byte == ESC {
+                    self.state = State::StringEscape;
+                }
+                None
+            }
+            State::StringEscape => {
+                if byte == b'\\' {
+                    self.state = State::Ground;
+                    None
+                } else {
+                    self.enter_escape();
+                    self.escape(byte)
+                }
+            }
+        }
+    }
+
+    fn enter_escape(&mut self) {
+        self.state = State::Escape;
+        self.private = None;
+        self.params.clear();
+        self.param_started = false;
+        self.intermediates.clear();
+    }
+
+    fn escape(&mut self, byte: u8) -> Option<Action> {
+        match byte {
+            ESC => None,
+            b'[' => {
+                self.state = State::Csi;
+                None
+            }
+            b']' => {
+                self.state = State::Osc;
+                None
+            }
+            b'P' | b'X' | b'^' | b'_' => {
+                self.state = State::String;
+                None
+            }
+            0x20..=0x2f => {
+                self.intermediates.push(byte);
+                self.state = State::EscapeIntermediate;
+                None
+            }
+            0x30..=0x7e => {
+                self.state = State::Ground;
+                Some(Action::Esc {
+                    intermediates: vec![],
+                    final_byte: byte,
+                })
+            }
+            0..=0x1f => Some(Action::Control(byte)),
+            _ => {
+                self.state = State::Ground;
+                None
+            }
+        }
+    }
+
+    fn csi(&mut self, byte: u8) -> Option<Action> {
+        match byte {
+            ESC => {
+                self.enter_escape();
+                None
+            }
+            b'0'..=b'9' => {
+                if !self.param_started {
+                    self.params.push(0);
+                    self.param_started = true;
+                }
+                if let Some(param) = self.params.last_mut() {
+                    *param = param
+                        .saturating_mul(10)
+                        .saturating_add(u16::from(byte - b'0'));
+                }
+                None
+            }
+            b';' | b':' => {
+                if !self.param_started {
+                    self.params.push(0);
+                }
+                self.param_started = false;
+                None
+            }
+            b'<'..=b'?' => {
+                if self.params.is_empty() && self.private.is_none() {
+                    self.private = Some(byte);
+                }
+                None
+            }
+            0x20..=0x2f => {
+                self.intermediates.push(byte);
+                None
+            }
+            0x40..=0x7e => {
+                self.state = State::Ground;
+                Some(Action::Csi {
+                    private: self.private.take(),
+                    params: std::mem::take(&mut self.params),
+                    final_byte: byte,
+                })
+            }
+            // Controls are carried out in the middle of a sequence.
+            0..=0x1f => Some(Action::Control(byte)),
+            _ => None,
+        }
+    }
+}
+
+/// Drop escape sequences and control characters from `content`, keeping `LF` and `TAB`.
+pub(crate) fn strip(content: &[u8]) -> Vec<u8> {
+    let mut parser = Parser::default();
+    let mut out = Vec::with_capacity(content.len());
+    for &byte in content {
+        out.extend(kept(parser.advance(byte)));
+    }
+    out
+}
+
+/// Where in `content` the byte at `index` in the output of `strip` comes from, the length of
+/// `content` if `strip` outputs fewer bytes.
+pub(crate) fn raw_offset(content: &[u8], index: usize) -> usize {
+    let mut parser = Parser::default();
+    let mut stripped = 0;
+    for (i, &byte) in content.iter().enumerate() {
+        if kept(parser.advance(byte)).is_some() {
+            if stripped == index {
+                return i;
+            }
+            stripped += 1;
+        }
+    }
+    content.len()
+}
+
+/// The byte `strip` keeps for `action`, if any.
+pub(crate) fn kept(action: Option<Action>) -> Option<u8> {
+    match action {
+        Some(Action::Print(b)) | Some(Action::Control(b @ (b'\n' | b'\t'))) => Some(b),
+        _ => None,
+    }
+}
+
+/// Drop escape sequences from `content`, keeping everything else.
+pub(crate) fn strip_sequences(content: &[u8]) -> Vec<u8> {
+    let mut parser = Parser::default();
+    let mut out = Vec::with_capacity(content.len());
+    for &byte in content {
+        if let Some(Action::Print(b) | Action::Control(b)) = parser.advance(byte) {
+            out.push(b);
+        }
+    }
+    out
+}
+
+/// Replay `content` the way a terminal shows it: `BS` and cursor moves go back and forth on
+/// the line, `CR` goes back to its start, text overwrites what is under the cursor and
+/// erase-in-line blanks it. Other escape sequences and control characters are dropped,
+/// `LF` and `TAB` are kept.
+pub(crate) fn render(content: &[u8]) -> Vec<u8> {
+    let mut parser = Parser::default();
+    let mut line = Line::default();
+    let mut out = Vec::with_capacity(content.len());
+    for &byte in content {
+        match parser.advance(byte) {
+            Some(Action::Print(b)) | Some(Action::Control(b @ b'\t')) => line.print(b),
+            Some(Action::Control(b'\n')) => {
+                line.flush(&mut out);
+                out.push(b'\n');
+            }
+            Some(Action::Control(b'\r')) => line.cursor = 0,
+            Some(Action::Control(BS)) => line.cursor = line.cursor.saturating_sub(1),
+            Some(Action::Csi {
+                private: None,
+                params,
+                final_byte,
+            }) => {
+                let n = usize::from(params.first().copied().unwrap_or(0));
+                match final_byte {
+                    b'K' => line.erase(n),
+                    b'C' => line.cursor += n.max(1),
+                    b'D' => line.cursor = line.cursor.saturating_sub(n.max(1)),
+                    b'G' => line.cursor = n.max(1) - 1,
+                    _ => {}
+                }
+            }
+            // `NUL` (sent after a bare `CR`), `BEL` and the like show nothing.
+            _ => {}
+        }
+    }
+    line.flush(&mut out);
+    out
+}
+
+// The length of the UTF-8 character starting with `lead`, 1 for anything else.
+fn utf8_len(lead: u8) -> usize {
+    match lead {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => 1,
+    }
+}
+
+// The line being drawn by `render`, one character per cell.
+#[derive(Debug, Default)]
+struct Line {
+    cells: Vec<Vec<u8>>,
+    cursor: usize,
+}
+
+impl Line {
+    fn print(&mut self, byte: u8) {
+        // The rest of a UTF-8 character goes in the cell of its first byte.
+        if (0x80..0xc0).contains(&byte) {
+            if let Some(cell) = self
+                .cursor
+                .checked_sub(1)
+                .and_then(|i| self.cells.get_mut(i))
+            {
+                if cell.len() < utf8_len(cell[0]) {
+                    cell.push(byte);
+                    return;
+                }
+            }
+        }
+        if self.cells.len() < self.cursor {
+            self.cells.resize(self.cursor, vec![b' ']);
+        }
+        match self.cells.get_mut(self.cursor) {
+            Some(cell) => *cell = vec![byte],
+            None => self.cells.push(vec![byte]),
+        }
+        self.cursor += 1;
+    }
+
+    fn erase(&mut self, mode: usize) {
+        match mode {
+            0 => self.cells.truncate(self.cursor),
+            1 => {
+                let end = (self.cursor + 1).min(self.cells.len());
+                self.cells[..end].fill(vec![b' ']);
+            }
+            _ => self.cells.clear(),
+        }
+    }
+
+    fn flush(&mut self, out: &mut Vec<u8>) {
+        for cell in self.cells.drain(..) {
+            out.extend_from_slice(&cell);
+        }
+        self.cursor = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_sequences_keeps_del_can_and_sub() {
+        assert_eq!(strip_sequences(b"a\x7fb\x18c\x1a"), b"a\x7fb\x18c\x1a");
+        // CAN aborts the sequence it interrupts.
+        assert_eq!(strip_sequences(b"\x1b[3\x18m"), b"\x18m");
+    }
+
+    fn actions(content: &[u8]) -> Vec<Action> {
+        let mut parser = Parser::default();
+        content.iter().filter_map(|&b| parser.advance(b)).collect()
+    }
+
+    #[test]
+    fn parses_sgr() {
+        assert_eq!(
+            actions(b"\x1b[0;32m"),
+            vec![Action::Csi {
+                private: None,
+                params: vec![0, 32],
+                final_byte: b'm',
+            }]
+        );
+        assert_eq!(
+            actions(b"\x1b[1m"),
+            vec![Action::Csi {
+                private: None,
+                params: vec![1],
+                final_byte: b'm',
+            }]
+        );
+    }
+
+    #[test]
+    fn parses_erase_line_without_params() {
+        assert_eq!(
+            actions(b"\x1b[K"),
+            vec![Action::Csi {
+                private: None,
+                params: vec![],
+                final_byte: b'K',
+            }]
+        );
+    }
+
+    #[test]
+    fn parses_private_mode() {
+        assert_eq!(
+            actions(b"\x1b[?25h"),
+            vec![Action::Csi {
+                private: Some(b'?'),
+                params: vec![25],
+                final_byte: b'h',
+            }]
+        );
+    }
+
+    #[test]
+    fn parses_osc_title() {
+        assert_eq!(actions(b"\x1b]0;host: ~\x07"), vec![Action::Osc]);
+        assert_eq!(
+            actions(b"\x1b]2;title\x1b\\a"),
+            vec![Action::Osc, Action::Print(b'a')]
+        );
+    }
+
+    #[test]
+    fn sequences_split_across_calls() {
+        let mut parser = Parser::default();
+        assert_eq!(parser.advance(0x1b), None);
+        assert_eq!(parser.advance(b'['), None);
+        assert_eq!(parser.advance(b'3'), None);
+        assert_eq!(parser.advance(b'2'), None);
+        assert_eq!(
+            parser.advance(b'm'),
+            Some(Action::Csi {
+                private: None,
+                params: vec![32],
+                final_byte: b'm',
+            })
+        );
+    }
+
+    #[test]
+    fn strip_drops_sequences_and_controls() {
+        assert_eq!(
+            strip(b"\x1b]0;t\x07\x1b[0;32mok\x1b[0m\x1b[?25h\r\n\tx\x07"),
+            b"ok\n\tx"
+        );
+    }
+
+    #[test]
+    fn strip_sequences_keeps_controls() {
+        assert_eq!(strip_sequences(b"\x1b[1mok\x1b[K\r\n"), b"ok\r\n");
+    }
+
+    #[test]
+    fn render_backspace_overwrites() {
+        assert_eq!(render(b"abc\x08\x08X\n"), b"aXc\n");
+    }
+
+    #[test]
+    fn render_carriage_return_overwrites() {
+        assert_eq!(render(b"12345\rab\n"), b"ab345\n");
+    }
+
+    #[test]
+    fn render_erase_line() {
+        assert_eq!(render(b"--More--\r\x1b[Kline\n"), b"line\n");
+        assert_eq!(render(b"abcdef\x1b[3D\x1b[K!\n"), b"abc!\n");
+        assert_eq!(render(b"abcdef\x1b[3D\x1b[1K\n"), b"    ef\n");
+    }
+
+    #[test]
+    fn render_keeps_utf8_in_one_cell() {
+        assert_eq!(render("né\x08e\n".as_bytes()), b"ne\n");
+    }
+}
@@ -0,0 +1,232 @@
+//! Character encodings of the server output and of the commands sent to it.
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use encoding::all::{GB18030, GBK, UTF_8};
+use encoding::codec::singlebyte::SingleByteEncoding;
+use encoding::label::encoding_from_whatwg_label;
+use encoding::{DecoderTrap, EncoderTrap};
+
+use crate::error::TelnetError;
+
+/// The encodings of the `encoding` crate.
+pub use encoding::all;
+pub use encoding::EncodingRef;
+
+/// IBM code page 437, the DOS character set with box drawing characters.
+pub const CP437: EncodingRef = &SingleByteEncoding {
+    name: "ibm437",
+    whatwg_name: None,
+    index_forward: cp437_forward,
+    index_backward: cp437_backward,
+};
+
+// Characters of the bytes 0x80 to 0xff of code page 437.
+const CP437_HIGH: [u16; 128] = [
+    0x00c7, 0x00fc, 0x00e9, 0x00e2, 0x00e4, 0x00e0, 0x00e5, 0x00e7, 0x00ea, 0x00eb, 0x00e8, 0x00ef,
+    0x00ee, 0x00ec, 0x00c4, 0x00c5, 0x00c9, 0x00e6, 0x00c6, 0x00f4, 0x00f6, 0x00f2, 0x00fb, 0x00f9,
+    0x00ff, 0x00d6, 0x00dc, 0x00a2, 0x00a3, 0x00a5, 0x20a7, 0x0192, 0x00e1, 0x00ed, 0x00f3, 0x00fa,
+    0x00f1, 0x00d1, 0x00aa, 0x00ba, 0x00bf, 0x2310, 0x00ac, 0x00bd, 0x00bc, 0x00a1, 0x00ab, 0x00bb,
+    0x2591, 0x2592, 0x2593, 0x2502, 0x2524, 0x2561, 0x2562, 0x2556, 0x2555, 0x2563, 0x2551, 0x2557,
+    0x255d, 0x255c, 0x255b, 0x2510, 0x2514, 0x2534, 0x252c, 0x251c, 0x2500, 0x253c, 0x255e, 0x255f,
+    0x255a, 0x2554, 0x2569, 0x2566, 0x2560, 0x2550, 0x256c, 0x2567, 0x2568, 0x2564, 0x2565, 0x2559,
+    0x2558, 0x2552, 0x2553, 0x256b, 0x256a, 0x2518, 0x250c, 0x2588, 0x2584, 0x258c, 0x2590, 0x2580,
+    0x03b1, 0x00df, 0x0393, 0x03c0, 0x03a3, 0x03c3, 0x00b5, 0x03c4, 0x03a6, 0x0398, 0x03a9, 0x03b4,
+    0x221e, 0x03c6, 0x03b5, 0x2229, 0x2261, 0x00b1, 0x2265, 0x2264, 0x2320, 0x2321, 0x00f7, 0x2248,
+    0x00b0, 0x2219, 0x00b7, 0x221a, 0x207f, 0x00b2, 0x25a0, 0x00a0,
+];
+
+fn cp437_forward(code: u8) -> u16 {
+    CP437_HIGH[usize::from(code - 0x80)]
+}
+
+fn cp437_backward(code: u32) -> u8 {
+    CP437_HIGH
+        .iter()
+        .position(|&c| u32::from(c) == code)
+        .map_or(0, |i| 0x80 + i as u8)
+}
+
+/// The encoding of a character set name, such as `UTF-8`, `ISO-8859-1` or `Shift_JIS`.
+/// Names are looked up as WHATWG labels, plus `IBM437` and `CP437` for code page 437.
+pub fn by_name(name: &str) -> Option<EncodingRef> {
+    match name.to_ascii_lowercase().as_str() {
+        "ibm437" | "cp437" | "437" | "cspc8codepage437" => Some(CP437),
+        _ => encoding_from_whatwg_label(name),
+    }
+}
+
+/// The character set agreed on through CHARSET, shared with its option handler.
+pub(crate) type Negotiated = Arc<Mutex<Option<(String, EncodingRef)>>>;
+
+/// The encodings tried in order on the server output, the first one also encodes commands.
+#[derive(Clone)]
+pub(crate) struct Charsets {
+    encodings: Vec<EncodingRef>,
+    // Replace what cannot be decoded or encoded instead of failing.
+    lossy: bool,
+    // Once set, it is the only encoding used.
+    negotiated: Negotiated,
+}
+
+impl Default for Charsets {
+    fn default() -> Self {
+        Charsets {
+            encodings: vec![UTF_8, GBK, GB18030],
+            lossy: false,
+            negotiated: Negotiated::default(),
+        }
+    }
+}
+
+impl fmt::Debug for Charsets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Charsets")
+            .field("encodings", &self.names())
+            .field("lossy", &self.lossy)
+            .field("negotiated", &self.negotiated_name())
+            .finish()
+    }
+}
+
+impl Charsets {
+    /// Replace the encodings, an empty list keeps the current ones.
+    pub(crate) fn set(&mut self, encodings: Vec<EncodingRef>) {
+        if !encodings.is_empty() {
+            self.encodings = encodings;
+        }
+    }
+
+    pub(crate) fn set_lossy(&mut self, lossy: bool) {
+        self.lossy = lossy;
+    }
+
+    /// Where the CHARSET handler stores the character set agreed on.
+    pub(crate) fn negotiated(&self) -> Negotiated {
+        self.negotiated.clone()
+    }
+
+    /// The name of the character set agreed on through CHARSET.
+    pub(crate) fn negotiated_name(&self) -> Option<String> {
+        let negotiated = self.negotiated.lock().ok()?;
+        negotiated.as_ref().map(|(name, _)| name.clone())
+    }
+
+    /// The encoding of commands, and of the emulated screen.
+    pub(crate) fn primary(&self) -> EncodingRef {
+        self.current()[0]
+    }
+
+    // The negotiated encoding if any, the configured ones otherwise.
+    fn current(&self) -> Vec<EncodingRef> {
+        match self
+            .negotiated
+            .lock()
+            .ok()
+            .and_then(|n| n.as_ref().map(|(_, e)| *e))
+        {
+            Some(encoding) => vec![encoding],
+            None => self.encodings.clone(),
+        }
+    }
+
+    /// Decode with the first encoding that fits the whole of `bytes`. In lossy mode, bytes
+    /// no encoding fits are decoded with the first one, invalid sequences becoming U+FFFD.
+    pub(crate) fn decode(&self, bytes: &[u8]) -> Result<String, TelnetError> {
+        let encodings = self.current();
+        for encoding in encodings.iter() {
+            if let Ok(text) = encoding.decode(bytes, DecoderTrap::Strict) {
+                return Ok(text);
+            }
+        }
+        if self.lossy {
+            if let Ok(text) = encodings[0].decode(bytes, DecoderTrap::Replace) {
+                return Ok(text);
+            }
+        }
+        let names: Vec<_> = encodings.iter().map(|e| e.name()).collect();
+        Err(TelnetError::DecodeError(names.join(", ")))
+    }
+
+    /// Encode with the first encoding, in lossy mode missing characters are sent as `?`.
+    pub(crate) fn encode(&self, text: &str) -> Result<Vec<u8>, TelnetError> {
+        let trap = if self.lossy {
+            EncoderTrap::Replace
+        } else {
+            EncoderTrap::Strict
+        };
+        let encoding = self.primary();
+        encoding
+            .encode(text, trap)
+            .map_err(|_| TelnetError::EncodeError(encoding.name().to_string()))
+    }
+
+    fn names(&self) -> Vec<&'static str> {
+        self.encodings.iter().map(|e| e.name()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding::all::ISO_8859_1;
+
+    #[test]
+    fn decode_falls_back_in_order() {
+        let charsets = Charsets::default();
+        assert_eq!(charsets.decode("é".as_bytes()).unwrap(), "é");
+        // Not UTF-8, decoded as GBK.
+        assert_eq!(charsets.decode(b"\xc4\xe3\xba\xc3").unwrap(), "你好");
+        assert!(matches!(
+            charsets.decode(b"\xff"),
+            Err(TelnetError::DecodeError(_))
+        ));
+    }
+
+    #[test]
+    fn lossy_decode_and_encode() {
+        let mut charsets = Charsets::default();
+        charsets.set(vec![UTF_8]);
+        charsets.set_lossy(true);
+        assert_eq!(charsets.decode(b"a\xffb").unwrap(), "a\u{fffd}b");
+
+        charsets.set(vec![ISO_8859_1]);
+        assert_eq!(charsets.encode("a€").unwrap(), b"a?");
+        charsets.set_lossy(false);
+        assert!(matches!(
+            charsets.encode("a€"),
+            Err(TelnetError::EncodeError(_))
+        ));
+    }
+
+    #[test]
+    fn empty_list_keeps_the_encodings() {
+        let mut charsets = Charsets::default();
+        charsets.set(vec![]);
+        assert_eq!(charsets.names(), vec!["utf-8", "gbk", "gb18030"]);
+    }
+
+    #[test]
+    fn negotiated_encoding_wins() {
+        let charsets = Charsets::default();
+        *charsets.negotiated().lock().unwrap() = Some(("latin1".to_string(), ISO_8859_1));
+        assert_eq!(charsets.decode(b"\xe9").unwrap(), "é");
+        assert_eq!(charsets.negotiated_name().as_deref(), Some("latin1"));
+    }
+
+    #[test]
+    fn cp437() {
+        assert_eq!(by_name("IBM437").map(|e| e.name()), Some("ibm437"));
+        assert_eq!(by_name("cp437").map(|e| e.name()), Some("ibm437"));
+        let text = CP437
+            .decode(b"a\x80\xc4\xdb\xff", DecoderTrap::Strict)
+            .unwrap();
+        assert_eq!(text, "aÇ─█\u{a0}");
+        assert_eq!(
+            CP437.encode(&text, EncoderTrap::Strict).unwrap(),
+            b"a\x80\xc4\xdb\xff"
+        );
+    }
+}
@@ -1,31 +1,111 @@
-use bytes::{Buf, BytesMut};
-use tokio_util::codec::Decoder;
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
 
 use crate::error::TelnetError;
 
+/// Interpret As Command.
+pub const IAC: u8 = 255;
+/// Indicates the demand that the other party stop performing the indicated option.
+pub const DONT: u8 = 254;
+/// Indicates the request that the other party perform the indicated option.
+pub const DO: u8 = 253;
+/// Indicates the refusal to perform the indicated option.
+pub const WONT: u8 = 252;
+/// Indicates the desire to begin performing the indicated option.
+pub const WILL: u8 = 251;
+/// Indicates that what follows is subnegotiation of the indicated option.
+pub const SB: u8 = 250;
+/// Go ahead.
+pub const GA: u8 = 249;
+/// Erase line.
+pub const EL: u8 = 248;
+/// Erase character.
+pub const EC: u8 = 247;
+/// Are you there.
+pub const AYT: u8 = 246;
+/// Abort output.
+pub const AO: u8 = 245;
+/// Interrupt process.
+pub const IP: u8 = 244;
+/// NVT character BRK.
+pub const BRK: u8 = 243;
+/// The data stream portion of a Synch.
+pub const DM: u8 = 242;
+/// No operation.
+pub const NOP: u8 = 241;
+/// End of subnegotiation parameters.
+pub const SE: u8 = 240;
+
+/// Telnet protocol codec, decodes `Item`s and encodes `Message`s.
 pub struct TelnetCodec {
-    sb_flag: bool,
+    // Option code and payload of an open `IAC SB`.
+    subnegotiation: Option<(u8, Vec<u8>)>,
     current_line: Vec<u8>,
 }
 
 impl Default for TelnetCodec {
     fn default() -> Self {
         TelnetCodec {
-            sb_flag: false,
+            subnegotiation: None,
             current_line: Vec::with_capacity(1024),
         }
     }
 }
 
-#[derive(Debug)]
+impl TelnetCodec {
+    fn take_line(&mut self) -> Item {
+        let line = self.current_line.to_vec();
+        self.current_line.clear();
+        Item::Line(line)
+    }
+}
+
+/// Inbound items produced by the `TelnetCodec` decoder.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Item {
     Line(Vec<u8>),
-    SE(u8),
-    SB(u8),
+    /// `IAC SB <option> <payload> IAC SE`, the payload is already unescaped.
+    Subnegotiation(u8, Vec<u8>),
     Will(u8),
     Wont(u8),
     Do(u8),
     Dont(u8),
+    /// `IAC NOP`
+    Nop,
+    /// `IAC DM`, the data stream portion of a Synch.
+    DataMark,
+    /// `IAC BRK`
+    Break,
+    /// `IAC IP`
+    InterruptProcess,
+    /// `IAC AO`
+    AbortOutput,
+    /// `IAC AYT`
+    AreYouThere,
+    /// `IAC EC`
+    EraseCharacter,
+    /// `IAC EL`
+    EraseLine,
+    /// `IAC GA`
+    GoAhead,
+    /// Any other two byte command, such as `IAC EOR` or the `EOF`, `SUSP` and `ABORT` of
+    /// RFC 1184.
+    Command(u8),
+}
+
+/// Outbound messages accepted by the `TelnetCodec` encoder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Plain data, every `0xff` byte is escaped as `IAC IAC`.
+    Data(Vec<u8>),
+    Will(u8),
+    Wont(u8),
+    Do(u8),
+    Dont(u8),
+    /// `IAC SB <option> <payload> IAC SE`, the payload is escaped like `Data`.
+    Subnegotiation(u8, Vec<u8>),
+    /// A bare two byte command, such as `IAC NOP` or `IAC AYT`.
+    Command(u8),
 }
 
 impl Decoder for TelnetCodec {
@@ -35,58 +115,96 @@ impl Decoder for TelnetCodec {
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         loop {
             if src.is_empty() {
-                return Ok(None);
+                // Hand out what is buffered, the rest of the line may never come (e.g. a prompt
+                // followed by `IAC GA`).
+                if self.current_line.is_empty() {
+                    return Ok(None);
+                }
+                return Ok(Some(self.take_line()));
             }
             if src[0] == 0xff {
+                // `IAC IAC` is an escaped 0xff data byte.
+                if src.len() > 1 && src[1] == IAC {
+                    src.advance(2);
+                    match self.subnegotiation.as_mut() {
+                        Some((_, payload)) => payload.push(IAC),
+                        None => self.current_line.push(IAC),
+                    }
+                    continue;
+                }
+                // Keep the order of data and commands.
+                if !self.current_line.is_empty() {
+                    return Ok(Some(self.take_line()));
+                }
                 let (res, consume) = try_parse_iac(src.chunk());
                 src.advance(consume);
                 match res {
-                    ParseIacResult::Invalid(err) => {
-                        return Err(TelnetError::UnknownIAC(err));
-                    }
                     ParseIacResult::NeedMore => return Ok(None),
-                    ParseIacResult::Item(item) => {
-                        if matches!(item, Item::SB(_)) {
-                            self.sb_flag = true;
-                            continue;
-                        } else if matches!(item, Item::SE(_)) {
-                            self.sb_flag = false;
-                            continue;
-                        }
-                        return Ok(Some(item));
+                    ParseIacResult::SubBegin(opt) => {
+                        self.subnegotiation = Some((opt, Vec::new()));
+                        continue;
                     }
+                    ParseIacResult::SubEnd => match self.subnegotiation.take() {
+                        Some((opt, payload)) => {
+                            return Ok(Some(Item::Subnegotiation(opt, payload)));
+                        }
+                        // Stray `IAC SE`, nothing to close.
+                        None => continue,
+                    },
+                    ParseIacResult::Item(item) => return Ok(Some(item)),
                 }
-            } else if self.sb_flag {
-                src.chunk();
-                src.advance(1);
+            } else if let Some((_, payload)) = self.subnegotiation.as_mut() {
+                let len = src.iter().position(|b| *b == IAC).unwrap_or(src.len());
+                payload.extend_from_slice(&src[..len]);
+                src.advance(len);
                 continue;
             } else {
                 let byte = src.get_u8();
-                match byte {
-                    10 => {
-                        self.current_line.push(byte);
-                        let line = self.current_line.to_vec();
-                        self.current_line.clear();
-                        return Ok(Some(Item::Line(line)));
-                    }
-                    0..=31 => {}
-                    _ => {
-                        self.current_line.push(byte);
-                        if src.is_empty() {
-                            let line = self.current_line.to_vec();
-                            self.current_line.clear();
-                            return Ok(Some(Item::Line(line)));
-                        }
-                    }
+                self.current_line.push(byte);
+                if byte == 10 {
+                    return Ok(Some(self.take_line()));
                 }
             }
         }
     }
 }
 
+impl Encoder<Message> for TelnetCodec {
+    type Error = TelnetError;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match msg {
+            Message::Data(data) => put_escaped(&data, dst),
+            Message::Will(opt) => dst.put_slice(&[IAC, WILL, opt]),
+            Message::Wont(opt) => dst.put_slice(&[IAC, WONT, opt]),
+            Message::Do(opt) => dst.put_slice(&[IAC, DO, opt]),
+            Message::Dont(opt) => dst.put_slice(&[IAC, DONT, opt]),
+            Message::Subnegotiation(opt, payload) => {
+                dst.put_slice(&[IAC, SB, opt]);
+                put_escaped(&payload, dst);
+                dst.put_slice(&[IAC, SE]);
+            }
+            Message::Command(cmd) => dst.put_slice(&[IAC, cmd]),
+        }
+        Ok(())
+    }
+}
+
+// Copy `data` into `dst`, doubling every `IAC` byte.
+fn put_escaped(data: &[u8], dst: &mut BytesMut) {
+    dst.reserve(data.len());
+    for chunk in data.split_inclusive(|b| *b == IAC) {
+        dst.put_slice(chunk);
+        if chunk.last() == Some(&IAC) {
+            dst.put_u8(IAC);
+        }
+    }
+}
+
 enum ParseIacResult {
-    Invalid(String),
     Item(Item),
+    SubBegin(u8),
+    SubEnd,
     NeedMore,
 }
 
@@ -101,28 +219,166 @@ fn try_parse_iac(bytes: &[u8]) -> (ParseIacResult, usize) {
         return (ParseIacResult::NeedMore, 0);
     }
 
-    if is_sub(bytes[1]) && bytes.len() < 3 {
-        return (ParseIacResult::NeedMore, 0);
-    }
-
     match bytes[1] {
-        240 => (ParseIacResult::Item(Item::SE(bytes[2])), 2),
-        250 => (ParseIacResult::Item(Item::SB(bytes[2])), 2),
-        251 => (ParseIacResult::Item(Item::Will(bytes[2])), 3),
-        252 => (ParseIacResult::Item(Item::Wont(bytes[2])), 3),
-        253 => (ParseIacResult::Item(Item::Do(bytes[2])), 3),
-        254 => (ParseIacResult::Item(Item::Dont(bytes[2])), 3),
-        cmd => (
-            ParseIacResult::Invalid(format!("Unknown IAC command {}.", cmd)),
-            0,
-        ),
+        SE => (ParseIacResult::SubEnd, 2),
+        SB => (ParseIacResult::SubBegin(bytes[2]), 3),
+        WILL => (ParseIacResult::Item(Item::Will(bytes[2])), 3),
+        WONT => (ParseIacResult::Item(Item::Wont(bytes[2])), 3),
+        DO => (ParseIacResult::Item(Item::Do(bytes[2])), 3),
+        DONT => (ParseIacResult::Item(Item::Dont(bytes[2])), 3),
+        NOP => (ParseIacResult::Item(Item::Nop), 2),
+        DM => (ParseIacResult::Item(Item::DataMark), 2),
+        BRK => (ParseIacResult::Item(Item::Break), 2),
+        IP => (ParseIacResult::Item(Item::InterruptProcess), 2),
+        AO => (ParseIacResult::Item(Item::AbortOutput), 2),
+        AYT => (ParseIacResult::Item(Item::AreYouThere), 2),
+        EC => (ParseIacResult::Item(Item::EraseCharacter), 2),
+        EL => (ParseIacResult::Item(Item::EraseLine), 2),
+        GA => (ParseIacResult::Item(Item::GoAhead), 2),
+        cmd => (ParseIacResult::Item(Item::Command(cmd)), 2),
     }
 }
 
 fn is_three_byte_iac(byte: u8) -> bool {
-    matches!(byte, 251..=254)
+    matches!(byte, SB..=DONT)
 }
 
-fn is_sub(byte: u8) -> bool {
-    byte == 240 || byte == 250
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decode everything `src` holds so far.
+    fn decode_all(codec: &mut TelnetCodec, src: &mut BytesMut) -> Vec<Item> {
+        let mut items = vec![];
+        while let Some(item) = codec.decode(src).unwrap() {
+            items.push(item);
+        }
+        items
+    }
+
+    #[test]
+    fn escaped_iac_in_data() {
+        let mut codec = TelnetCodec::default();
+        let mut src = BytesMut::from(&b"a\xff\xffb\r\n"[..]);
+        assert_eq!(
+            decode_all(&mut codec, &mut src),
+            vec![Item::Line(b"a\xffb\r\n".to_vec())]
+        );
+    }
+
+    #[test]
+    fn escaped_iac_in_subnegotiation() {
+        let mut codec = TelnetCodec::default();
+        let mut src = BytesMut::from(&b"\xff\xfa\x18\x00a\xff\xffb\xff\xf0"[..]);
+        assert_eq!(
+            decode_all(&mut codec, &mut src),
+            vec![Item::Subnegotiation(24, b"\x00a\xffb".to_vec())]
+        );
+    }
+
+    #[test]
+    fn subnegotiation_split_across_reads() {
+        let mut codec = TelnetCodec::default();
+        let mut src = BytesMut::from(&b"\xff\xfa\x18\x00xt"[..]);
+        assert!(decode_all(&mut codec, &mut src).is_empty());
+        src.extend_from_slice(b"erm\xff\xf0ok\n");
+        assert_eq!(
+            decode_all(&mut codec, &mut src),
+            vec![
+                Item::Subnegotiation(24, b"\x00xterm".to_vec()),
+                Item::Line(b"ok\n".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn lone_trailing_iac_waits_for_more() {
+        let mut codec = TelnetCodec::default();
+        let mut src = BytesMut::from(&b"ab\xff"[..]);
+        assert_eq!(
+            decode_all(&mut codec, &mut src),
+            vec![Item::Line(b"ab".to_vec())]
+        );
+        assert_eq!(&src[..], b"\xff");
+        src.extend_from_slice(b"\xfb\x01");
+        assert_eq!(decode_all(&mut codec, &mut src), vec![Item::Will(1)]);
+    }
+
+    #[test]
+    fn stray_subnegotiation_end_is_ignored() {
+        let mut codec = TelnetCodec::default();
+        let mut src = BytesMut::from(&b"\xff\xf0a\n"[..]);
+        assert_eq!(
+            decode_all(&mut codec, &mut src),
+            vec![Item::Line(b"a\n".to_vec())]
+        );
+    }
+
+    #[test]
+    fn commands() {
+        let mut codec = TelnetCodec::default();
+        let mut src = BytesMut::from(
+            &b"\xff\xf1\xff\xf9\xff\xf2\xff\xf3\xff\xf4\xff\xf5\xff\xf6\xff\xf7\xff\xf8"[..],
+        );
+        assert_eq!(
+            decode_all(&mut codec, &mut src),
+            vec![
+                Item::Nop,
+                Item::GoAhead,
+                Item::DataMark,
+                Item::Break,
+                Item::InterruptProcess,
+                Item::AbortOutput,
+                Item::AreYouThere,
+                Item::EraseCharacter,
+                Item::EraseLine,
+            ]
+        );
+    }
+
+    #[test]
+    fn other_commands_do_not_stop_decoding() {
+        let mut codec = TelnetCodec::default();
+        // EOR, then the EOF of RFC 1184.
+        let mut src = BytesMut::from(&b"a\r\n\xff\xefb\xff\xeca\n"[..]);
+        assert_eq!(
+            decode_all(&mut codec, &mut src),
+            vec![
+                Item::Line(b"a\r\n".to_vec()),
+                Item::Command(239),
+                Item::Line(b"b".to_vec()),
+                Item::Command(236),
+                Item::Line(b"a\n".to_vec()),
+            ]
+        );
+    }
+
+    fn encode(msg: Message) -> Vec<u8> {
+        let mut dst = BytesMut::new();
+        TelnetCodec::default().encode(msg, &mut dst).unwrap();
+        dst.to_vec()
+    }
+
+    #[test]
+    fn data_doubles_iac() {
+        assert_eq!(
+            encode(Message::Data(b"a\xffb\xff".to_vec())),
+            b"a\xff\xffb\xff\xff"
+        );
+    }
+
+    #[test]
+    fn subnegotiation_payload_is_escaped() {
+        assert_eq!(
+            encode(Message::Subnegotiation(31, vec![0, 255, 0, 24])),
+            b"\xff\xfa\x1f\x00\xff\xff\x00\x18\xff\xf0"
+        );
+    }
+
+    #[test]
+    fn negotiation_and_commands() {
+        assert_eq!(encode(Message::Do(1)), b"\xff\xfd\x01");
+        assert_eq!(encode(Message::Wont(3)), b"\xff\xfc\x03");
+        assert_eq!(encode(Message::Command(AYT)), b"\xff\xf6");
+    }
 }
@@ -1,4 +1,4 @@
-use std::{io, string};
+use std::io;
 
 use thiserror::Error;
 
@@ -8,14 +8,24 @@ pub enum TelnetError {
     Timeout(String),
     #[error("io error.")]
     IOError(#[from] io::Error),
-    #[error("Parse string error.")]
-    ParseError(#[from] string::FromUtf8Error),
-    #[error("Unknown IAC command `{0}`.")]
-    UnknownIAC(String),
+    #[error("Decode output with `{0}` failed.")]
+    DecodeError(String),
+    #[error("Encode with `{0}` failed.")]
+    EncodeError(String),
+    #[error("Unknown charset `{0}`.")]
+    UnknownCharset(String),
     #[error("Authentication failed.")]
     AuthenticationFailed,
+    #[error("Invalid control character `{0}`.")]
+    InvalidControl(char),
     #[error("No more data.")]
     NoMoreData,
-    #[error("Init Color regex failed `{0}`.")]
+    #[error("Empty command.")]
+    EmptyCommand,
+    #[error("No exit status marker in the output.")]
+    NoStatusMarker,
+    #[error("Screen emulation is not enabled.")]
+    NoScreen,
+    #[error("Init regex failed `{0}`.")]
     RegexError(#[from] regex::Error),
 }
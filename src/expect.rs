@@ -0,0 +1,78 @@
+//! Patterns for `Telnet::expect`.
+use regex::bytes::Regex;
+
+use crate::error::TelnetError;
+
+/// Something to wait for in the output of the server.
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// Matches the exact text.
+    Literal(String),
+    /// Matches a regular expression, its capture groups end up in `Match::captures`.
+    Regex(Regex),
+}
+
+impl Pattern {
+    /// A pattern matching the exact text.
+    pub fn literal<T: ToString>(text: T) -> Pattern {
+        Pattern::Literal(text.to_string())
+    }
+
+    /// A pattern matching a regular expression.
+    pub fn regex(re: &str) -> Result<Pattern, TelnetError> {
+        Ok(Pattern::Regex(Regex::new(re)?))
+    }
+
+    // The first match in `haystack`.
+    pub(crate) fn find(&self, haystack: &[u8]) -> Option<Found> {
+        match self {
+            Pattern::Literal(text) => {
+                let needle = text.as_bytes();
+                if needle.is_empty() {
+                    return Some(Found::default());
+                }
+                let start = haystack.windows(needle.len()).position(|w| w == needle)?;
+                Some(Found {
+                    start,
+                    end: start + needle.len(),
+                    groups: vec![],
+                })
+            }
+            Pattern::Regex(re) => {
+                let caps = re.captures(haystack)?;
+                let whole = caps.get(0)?;
+                let groups = caps
+                    .iter()
+                    .skip(1)
+                    .map(|g| g.map(|m| m.as_bytes().to_vec()))
+                    .collect();
+                Some(Found {
+                    start: whole.start(),
+                    end: whole.end(),
+                    groups,
+                })
+            }
+        }
+    }
+}
+
+/// Where a pattern matched.
+#[derive(Debug, Default)]
+pub(crate) struct Found {
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+    pub(crate) groups: Vec<Option<Vec<u8>>>,
+}
+
+/// The outcome of `Telnet::expect`.
+#[derive(Debug, Clone)]
+pub struct Match {
+    /// Index of the pattern that matched.
+    pub index: usize,
+    /// The text received before the match.
+    pub before: String,
+    /// The matched text.
+    pub matched: String,
+    /// Capture groups of a `Pattern::Regex`, `None` for groups that did not take part.
+    pub captures: Vec<Option<String>>,
+}
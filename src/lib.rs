@@ -1,27 +1,63 @@
-mod codec;
+mod ansi;
+pub mod charset;
+pub mod codec;
 pub mod error;
+pub mod expect;
+mod negotiation;
+pub mod option;
+pub mod output;
+mod prompt;
+pub mod screen;
 
-use encoding::DecoderTrap;
-use encoding::{all::GB18030, all::GBK, Encoding};
-use futures::stream::StreamExt;
-use regex::bytes::Regex;
+use futures::{
+    sink::{Sink, SinkExt},
+    stream::{self, Stream, StreamExt},
+};
+use socket2::SockRef;
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tokio::{
-    io::AsyncWriteExt,
+    io::{AsyncWriteExt, Interest},
     net::TcpStream,
     time::{self, Duration},
 };
-use tokio_util::codec::FramedRead;
+use tokio_util::codec::Framed;
 
-use crate::codec::{Item, TelnetCodec};
+use crate::charset::{Charsets, EncodingRef};
+use crate::codec::{Item, Message, TelnetCodec, AO, AYT, BRK, DM, IAC, IP};
 use crate::error::TelnetError;
+use crate::expect::{Match, Pattern};
+use crate::negotiation::Negotiation;
+use crate::option::{
+    Accept, Charset, EnvVar, Naws, NewEnviron, OptionHandler, TerminalType, BINARY, ECHO, NAWS, SGA,
+};
+use crate::output::{CommandOutput, OutputFilter, Step, Transcript};
+use crate::prompt::{Pagers, Prompts};
+use crate::screen::Screen;
+
+// Window size (columns, rows) reported when none is configured.
+const DEFAULT_WINDOW_SIZE: (u16, u16) = (0xfc, 0x1b);
 
 #[derive(Debug, Default)]
 pub struct TelnetBuilder {
     prompts: Vec<String>,
+    prompts_regex: Vec<String>,
+    pagers: Vec<(String, String)>,
+    pagers_regex: Vec<(String, String)>,
     username_prompt: String,
     password_prompt: String,
     connect_timeout: Duration,
     timeout: Duration,
+    window_size: Option<(u16, u16)>,
+    screen: Option<(u16, u16)>,
+    terminal_types: Vec<String>,
+    env: Vec<EnvVar>,
+    charsets: Charsets,
+    charset_names: Vec<String>,
+    strip_escapes: bool,
+    handlers: Vec<Box<dyn OptionHandler>>,
 }
 
 impl TelnetBuilder {
@@ -38,6 +74,39 @@ impl TelnetBuilder {
         self
     }
 
+    /// Set the telnet server prompt as a regular expression, it must match the end of the line.
+    /// It is checked in addition to the prompts set with `prompt` or `prompts`.
+    pub fn prompt_regex<T: ToString>(mut self, prompt: T) -> TelnetBuilder {
+        self.prompts_regex = vec![prompt.to_string()];
+        self
+    }
+
+    /// Set the telnet server prompts as regular expressions, each must match the end of the line.
+    /// If `prompts_regex` is set, `prompt_regex` will be overwritten.
+    pub fn prompts_regex<T: ToString>(mut self, prompts: &[T]) -> TelnetBuilder {
+        self.prompts_regex = prompts.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    /// Add a pager marker, such as `--More--` or `Press any key to continue`, answered with
+    /// `response` (usually a space) during `execute`. The marker and the sequence erasing it
+    /// are removed from the output.
+    pub fn pager<M: ToString, R: ToString>(mut self, marker: M, response: R) -> TelnetBuilder {
+        self.pagers.push((marker.to_string(), response.to_string()));
+        self
+    }
+
+    /// Add a pager marker as a regular expression, it must match the end of the line.
+    pub fn pager_regex<M: ToString, R: ToString>(
+        mut self,
+        marker: M,
+        response: R,
+    ) -> TelnetBuilder {
+        self.pagers_regex
+            .push((marker.to_string(), response.to_string()));
+        self
+    }
+
     /// Login prompt, the common ones are `login: ` and `Password: ` or `Username:` and `Password:`.
     pub fn login_prompt(mut self, user_prompt: &str, pass_prompt: &str) -> TelnetBuilder {
         self.username_prompt = user_prompt.to_string();
@@ -57,35 +126,197 @@ impl TelnetBuilder {
         self
     }
 
+    /// Set the window size reported through NAWS, 252 columns and 27 rows by default.
+    pub fn window_size(mut self, cols: u16, rows: u16) -> TelnetBuilder {
+        self.window_size = Some((cols, rows));
+        self
+    }
+
+    /// Emulate a `cols` x `rows` terminal screen fed with everything the server sends,
+    /// see `Telnet::screen`. It is also the window size reported through NAWS unless
+    /// `window_size` is set. Set a terminal type such as `VT100` with `terminal_types`
+    /// so the server knows which sequences to use.
+    pub fn screen(mut self, cols: u16, rows: u16) -> TelnetBuilder {
+        self.screen = Some((cols, rows));
+        self
+    }
+
+    /// Set the terminal types reported through TTYPE, in order of preference.
+    /// TTYPE is refused when no type is set.
+    pub fn terminal_types<T: ToString>(mut self, types: &[T]) -> TelnetBuilder {
+        self.terminal_types = types.iter().map(|t| t.to_string()).collect();
+        self
+    }
+
+    /// Add a well-known variable (such as `USER` or `DISPLAY`) sent through NEW-ENVIRON.
+    /// Values never show up in `Debug` output.
+    pub fn env<K: ToString, V: ToString>(mut self, key: K, value: V) -> TelnetBuilder {
+        self.env
+            .push(EnvVar::var(key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Add a user defined variable sent through NEW-ENVIRON.
+    /// Values never show up in `Debug` output.
+    pub fn user_var<K: ToString, V: ToString>(mut self, key: K, value: V) -> TelnetBuilder {
+        self.env
+            .push(EnvVar::user_var(key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Set the encoding of the server output and of the commands sent, such as
+    /// `charset::all::ISO_8859_1`, `charset::all::WINDOWS_31J` (Shift-JIS) or `charset::CP437`.
+    /// UTF-8 is tried first by default, then GBK and GB18030.
+    pub fn encoding(mut self, encoding: EncodingRef) -> TelnetBuilder {
+        self.charsets.set(vec![encoding]);
+        self
+    }
+
+    /// Set the encodings tried in order on the server output, the first one that decodes
+    /// a whole line wins. Commands are encoded with the first one.
+    /// If `encodings` is set, `encoding` will be overwritten. An empty list is ignored and
+    /// the encodings set before are kept.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use mini_telnet::{charset, Telnet};
+    ///
+    /// let builder = Telnet::builder().encodings(&[charset::all::UTF_8, charset::CP437]);
+    /// ```
+    pub fn encodings(mut self, encodings: &[EncodingRef]) -> TelnetBuilder {
+        self.charsets.set(encodings.to_vec());
+        self
+    }
+
+    /// Never fail on output no encoding fits, it is decoded with the first encoding and
+    /// invalid sequences are replaced with U+FFFD. Characters of commands the first encoding
+    /// lacks are sent as `?`.
+    pub fn lossy(mut self, lossy: bool) -> TelnetBuilder {
+        self.charsets.set_lossy(lossy);
+        self
+    }
+
+    /// Set the character sets agreed on through CHARSET, in order of preference, such as
+    /// `UTF-8` or `ISO-8859-1`. Once the server agrees on one, it replaces the encodings set
+    /// with `encodings` both ways. CHARSET is refused when no character set is set.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # async fn run() -> Result<(), mini_telnet::error::TelnetError> {
+    /// use mini_telnet::Telnet;
+    ///
+    /// let mut telnet = Telnet::builder()
+    ///     .charsets(&["UTF-8", "Shift_JIS"])
+    ///     .connect("192.168.0.1:23")
+    ///     .await?;
+    /// let uptime = telnet.execute("uptime").await?;
+    /// println!("{:?}: {}", telnet.charset(), uptime);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn charsets<T: ToString>(mut self, names: &[T]) -> TelnetBuilder {
+        self.charset_names = names.iter().map(|n| n.to_string()).collect();
+        self
+    }
+
+    /// Remove escape sequences, such as colours and cursor movement, from the output of
+    /// `execute_bytes` and `normal_execute_bytes`. Every other byte is kept. Off by default.
+    pub fn strip_escapes(mut self, strip: bool) -> TelnetBuilder {
+        self.strip_escapes = strip;
+        self
+    }
+
+    /// Register a handler for a telnet option, it replaces the built-in one for the same option code.
+    pub fn option_handler<H: OptionHandler + 'static>(mut self, handler: H) -> TelnetBuilder {
+        self.handlers.push(Box::new(handler));
+        self
+    }
+
     /// Establish a connection with the remote telnetd.
     pub async fn connect(self, addr: &str) -> Result<Telnet, TelnetError> {
-        let clear = Clear::new()?;
-        match time::timeout(self.connect_timeout, TcpStream::connect(addr)).await {
-            Ok(res) => Ok(Telnet {
-                content: vec![],
-                stream: res?,
-                timeout: self.timeout,
-                prompts: self.prompts,
-                username_prompt: self.username_prompt,
-                password_prompt: self.password_prompt,
-                clear,
-            }),
-            Err(_) => Err(TelnetError::Timeout(format!(
-                "Connect remote addr({})",
-                addr
-            ))),
+        let prompts = Prompts::new(self.prompts, &self.prompts_regex)?;
+        let pagers = Pagers::new(self.pagers, self.pagers_regex)?;
+        let charset_list = self
+            .charset_names
+            .into_iter()
+            .map(|name| match charset::by_name(&name) {
+                Some(encoding) => Ok((name, encoding)),
+                None => Err(TelnetError::UnknownCharset(name)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let stream = match time::timeout(self.connect_timeout, TcpStream::connect(addr)).await {
+            Ok(res) => res?,
+            Err(_) => {
+                return Err(TelnetError::Timeout(format!(
+                    "Connect remote addr({})",
+                    addr
+                )))
+            }
+        };
+
+        let mut negotiation = Negotiation::default();
+        negotiation.register(Box::new(Accept::new(ECHO, false, true)));
+        negotiation.register(Box::new(Accept::new(SGA, true, true)));
+        negotiation.register(Box::new(Accept::new(BINARY, true, true)));
+        let (cols, rows) = self
+            .window_size
+            .or(self.screen)
+            .unwrap_or(DEFAULT_WINDOW_SIZE);
+        negotiation.register(Box::new(Naws::new(cols, rows)));
+        negotiation.register(Box::new(TerminalType::new(self.terminal_types)));
+        negotiation.register(Box::new(NewEnviron::new(self.env)));
+        negotiation.register(Box::new(Charset::new(
+            charset_list,
+            self.charsets.negotiated(),
+        )));
+        for handler in self.handlers {
+            negotiation.register(handler);
+        }
+        let mut stream = Framed::new(stream, TelnetCodec::default());
+        for msg in negotiation.start() {
+            stream.feed(msg).await?;
         }
+        stream.flush().await?;
+
+        Ok(Telnet {
+            content: vec![],
+            pending: vec![],
+            stream,
+            timeout: self.timeout,
+            prompts,
+            pagers,
+            username_prompt: self.username_prompt,
+            password_prompt: self.password_prompt,
+            negotiation,
+            screen: self
+                .screen
+                .map(|(cols, rows)| Screen::new(cols, rows, self.charsets.clone())),
+            charsets: self.charsets,
+            strip_escapes: self.strip_escapes,
+            transcript: None,
+        })
     }
 }
 
 pub struct Telnet {
     timeout: Duration,
     content: Vec<String>,
-    stream: TcpStream,
-    prompts: Vec<String>,
+    // Output read past the match of `expect`, handed out before anything else.
+    pending: Vec<u8>,
+    // Kept for the whole session, so buffered bytes and decoder state survive between calls.
+    stream: Framed<TcpStream, TelnetCodec>,
+    prompts: Prompts,
+    pagers: Pagers,
     username_prompt: String,
     password_prompt: String,
-    clear: Clear,
+    negotiation: Negotiation,
+    screen: Option<Screen>,
+    charsets: Charsets,
+    strip_escapes: bool,
+    // Set while `execute_detailed` runs.
+    transcript: Option<Transcript>,
 }
 
 impl Telnet {
@@ -102,10 +333,61 @@ impl Telnet {
         }
     }
 
+    // Next item from the server, output left over by `expect` comes first.
+    async fn next_item(&mut self) -> Option<Result<Item, TelnetError>> {
+        if !self.pending.is_empty() {
+            return Some(Ok(Item::Line(std::mem::take(&mut self.pending))));
+        }
+        self.next_framed().await
+    }
+
+    // Next item from the connection itself, `pending` is left alone.
+    async fn next_framed(&mut self) -> Option<Result<Item, TelnetError>> {
+        let item = self.stream.next().await;
+        if let (Some(screen), Some(Ok(Item::Line(line)))) = (&mut self.screen, &item) {
+            screen.feed(line);
+        }
+        item
+    }
+
+    // Next line (or piece of it) from the server, answering option negotiation on the way.
+    async fn next_line(&mut self) -> Result<Vec<u8>, TelnetError> {
+        loop {
+            match time::timeout(self.timeout, self.next_item()).await {
+                Ok(res) => match res {
+                    Some(item) => match item? {
+                        Item::Line(line) => {
+                            if let Some(transcript) = &mut self.transcript {
+                                transcript.data(&line);
+                            }
+                            return Ok(line);
+                        }
+                        item => {
+                            negotiate(&mut self.negotiation, &item, &mut self.stream).await?;
+                            if let Some(transcript) = &mut self.transcript {
+                                transcript.events.push(item);
+                            }
+                        }
+                    },
+                    None => return Err(TelnetError::NoMoreData),
+                },
+                Err(_) => return Err(TelnetError::Timeout("read next framed".to_string())),
+            }
+        }
+    }
+
+    /// Whether the server echoes what we send, that is it enabled the ECHO option.
+    pub fn server_echo(&self) -> bool {
+        self.negotiation.remote_enabled(ECHO)
+    }
+
     /// Login remote telnet daemon, only retry one time.
     /// # Examples
     ///
     /// ```no_run
+    /// # use std::time::Duration;
+    /// # use mini_telnet::Telnet;
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
     /// let mut client = Telnet::builder()
     ///     .prompt("username@hostname:$ ")
     ///     .login_prompt("login: ", "Password: ")
@@ -116,63 +398,40 @@ impl Telnet {
     ///     Ok(_) => println!("login success."),
     ///     Err(e) => println!("login failed: {}", e),
     /// };
+    /// # Ok(())
+    /// # }
     /// ```
     ///
     pub async fn login(&mut self, username: &str, password: &str) -> Result<(), TelnetError> {
-        let user = Telnet::format_enter_str(username);
-        let pass = Telnet::format_enter_str(password);
+        let user = self.charsets.encode(&Telnet::format_enter_str(username))?;
+        let pass = self.charsets.encode(&Telnet::format_enter_str(password))?;
 
         // Only retry one time, if password is input, then set with `true`;
         let mut auth_failed = false;
 
-        let (read, mut write) = self.stream.split();
-        let mut telnet = FramedRead::new(read, TelnetCodec::default());
-
         loop {
-            match time::timeout(self.timeout, telnet.next()).await {
+            match time::timeout(self.timeout, self.next_item()).await {
                 Ok(res) => {
                     match res {
-                        Some(res) => {
-                            match res? {
-                                Item::Do(i) | Item::Dont(i) => {
-                                    // set window size
-                                    if i == 0x1f {
-                                        write
-                                            .write_all(&[
-                                                0xff, 0xfb, 0x1f, 0xff, 0xfa, 0x1f, 0x00, 0xfc,
-                                                0x00, 0x1b, 0xff, 0xf0,
-                                            ])
-                                            .await?;
-                                    } else {
-                                        write.write_all(&[0xff, 0xfc, i]).await?;
+                        Some(res) => match res? {
+                            Item::Line(line) => {
+                                let line = ansi::strip(&line);
+                                if line.ends_with(self.username_prompt.as_bytes()) {
+                                    if auth_failed {
+                                        return Err(TelnetError::AuthenticationFailed);
                                     }
+                                    self.stream.send(Message::Data(user.clone())).await?;
+                                } else if line.ends_with(self.password_prompt.as_bytes()) {
+                                    self.stream.send(Message::Data(pass.clone())).await?;
+                                    auth_failed = true;
+                                } else if self.prompts.matches(&line) {
+                                    return Ok(());
                                 }
-                                Item::Will(i) | Item::Wont(i) => {
-                                    write.write_all(&[0xff, 0xfe, i]).await?;
-                                }
-                                Item::Line(line) => {
-                                    let line = self.clear.color(&line);
-                                    if line.ends_with(self.username_prompt.as_bytes()) {
-                                        if auth_failed {
-                                            return Err(TelnetError::AuthenticationFailed);
-                                        }
-                                        write.write_all(user.as_bytes()).await?;
-                                    } else if line.ends_with(self.password_prompt.as_bytes()) {
-                                        write.write_all(pass.as_bytes()).await?;
-                                        auth_failed = true;
-                                    } else if self
-                                        .prompts
-                                        .iter()
-                                        .filter(|p| line.ends_with(p.as_bytes()))
-                                        .count()
-                                        != 0
-                                    {
-                                        return Ok(());
-                                    }
-                                }
-                                item => return Err(TelnetError::UnknownIAC(format!("{:?}", item))),
                             }
-                        }
+                            item => {
+                                negotiate(&mut self.negotiation, &item, &mut self.stream).await?
+                            }
+                        },
                         None => return Err(TelnetError::NoMoreData),
                     };
                 }
@@ -182,86 +441,174 @@ impl Telnet {
     }
 
     /// Execute command, and filter it input message by line count.
+    /// Lines come out as a terminal shows them, backspaces, carriage returns and
+    /// erase-line sequences are applied (progress bars keep their last state).
     ///
     /// # Examples
     ///
     /// ```no_run
-    ///assert_eq!(telnet.execute("echo 'haha'").await?, "haha\n");
+    /// # async fn run(mut telnet: mini_telnet::Telnet) -> Result<(), mini_telnet::error::TelnetError> {
+    /// assert_eq!(telnet.execute("echo 'haha'").await?, "haha\n");
+    /// # Ok(())
+    /// # }
     /// ```
     ///
     pub async fn execute(&mut self, cmd: &str) -> Result<String, TelnetError> {
         let command = Telnet::format_enter_str(cmd);
-        let mut incomplete_line: Vec<u8> = vec![];
-        let mut line_feed_cnt = command.lines().count() as isize;
-        let mut real_output = false;
+        let filter = OutputFilter::new(&command, self.server_echo());
+        let (lines, _) = self.run_command(&command, filter).await?;
+        for line in lines {
+            self.content
+                .push(self.charsets.decode(&ansi::render(&line))?);
+        }
+        let result = self.content.join("");
+        self.content.clear();
+        Ok(result)
+    }
 
-        let (read, mut write) = self.stream.split();
-        match time::timeout(self.timeout, write.write(command.as_bytes())).await {
-            Ok(res) => res?,
-            Err(_) => return Err(TelnetError::Timeout("write cmd".to_string())),
-        };
-        let mut telnet = FramedRead::new(read, TelnetCodec::default());
+    /// Execute command like `execute` on a Unix shell, and return its exit status as well.
+    /// The command is run as `<cmd>; echo <marker>$?`, the marker line is removed from the
+    /// output. `TelnetError::NoStatusMarker` is returned if the prompt shows up without it.
+    ///
+    /// A blank `cmd` is rejected with `TelnetError::EmptyCommand`. After a trailing `&` or `;`
+    /// no other `;` is added, the status is then the one of starting the background job, or of
+    /// the last command. A `cmd` ending in a comment or an unclosed quote hides the marker.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # async fn run(mut telnet: mini_telnet::Telnet) -> Result<(), mini_telnet::error::TelnetError> {
+    /// let (output, status) = telnet.execute_with_status("grep -c eth0 /proc/net/dev").await?;
+    /// if status != 0 {
+    ///     println!("no eth0: {}", output);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    pub async fn execute_with_status(&mut self, cmd: &str) -> Result<(String, i32), TelnetError> {
+        let marker = status_marker();
+        let command = status_command(cmd, &marker).ok_or(TelnetError::EmptyCommand)?;
+        let output = self.execute(&command).await?;
+        // Output without a trailing newline ends up on the marker line.
+        output
+            .rsplit_once(&marker)
+            .and_then(|(output, status)| {
+                Some((output.to_string(), status.trim_end().parse().ok()?))
+            })
+            .ok_or(TelnetError::NoStatusMarker)
+    }
 
-        loop {
-            match time::timeout(self.timeout, telnet.next()).await {
-                Ok(res) => match res {
-                    Some(item) => {
-                        if let Item::Line(line) = item? {
-                            let mut line = self.clear.color(&line);
+    /// Execute command like `execute`, returning the output bytes as received.
+    /// Escape sequences are kept unless `TelnetBuilder::strip_escapes` is set.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # async fn run(mut telnet: mini_telnet::Telnet) -> Result<(), mini_telnet::error::TelnetError> {
+    /// let blob = telnet.execute_bytes("cat /etc/config.bin | xxd").await?;
+    /// std::fs::write("config.hex", blob)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    pub async fn execute_bytes(&mut self, cmd: &str) -> Result<Vec<u8>, TelnetError> {
+        let command = Telnet::format_enter_str(cmd);
+        let filter = OutputFilter::new(&command, self.server_echo());
+        let (lines, _) = self.run_command(&command, filter).await?;
+        Ok(self.join_bytes(lines))
+    }
 
-                            // ignore prompt line
-                            if self
-                                .prompts
-                                .iter()
-                                .filter(|p| line.ends_with(p.as_bytes()))
-                                .count()
-                                != 0
-                            {
-                                break;
-                            }
-                            // ignore command line echo
-                            if line.ends_with(&[10]) && line_feed_cnt > 0 {
-                                line_feed_cnt -= 1;
-                                if line_feed_cnt == 0 {
-                                    real_output = true;
-                                    continue;
-                                }
-                            }
+    /// Execute command like `execute`, also returning the prompt, the raw output, timings
+    /// and the option negotiation seen on the way.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # async fn run(mut telnet: mini_telnet::Telnet) -> Result<(), mini_telnet::error::TelnetError> {
+    /// let out = telnet.execute_detailed("show version").await?;
+    /// println!("{} ended by {:?} after {:?}", out.output, out.prompt, out.duration);
+    /// if out.output.is_empty() {
+    ///     println!("raw: {:?}, events: {:?}", out.raw, out.events);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    pub async fn execute_detailed(&mut self, cmd: &str) -> Result<CommandOutput, TelnetError> {
+        let command = Telnet::format_enter_str(cmd);
+        let filter = OutputFilter::new(&command, self.server_echo());
 
-                            if !real_output {
-                                continue;
-                            }
+        let start = time::Instant::now();
+        self.transcript = Some(Transcript::default());
+        let res = self.run_command(&command, filter).await;
+        let transcript = self.transcript.take().unwrap_or_default();
+        let (lines, prompt) = res?;
 
-                            if !line.ends_with(&[10]) || !incomplete_line.is_empty() {
-                                incomplete_line.append(&mut line);
-                            } else {
-                                self.content.push(decode(&line)?);
-                                continue;
-                            }
-                            // ignore command line
-                            if self
-                                .prompts
-                                .iter()
-                                .filter(|p| incomplete_line.ends_with(p.as_bytes()))
-                                .count()
-                                != 0
-                            {
-                                break;
-                            }
-                            if incomplete_line.ends_with(&[10]) {
-                                self.content.push(decode(&incomplete_line)?);
-                                incomplete_line.clear();
-                            }
+        let mut output = String::new();
+        for line in lines {
+            output.push_str(&self.charsets.decode(&ansi::render(&line))?);
+        }
+        Ok(CommandOutput {
+            output,
+            prompt: self.charsets.decode(&prompt)?,
+            raw: transcript.raw,
+            first_byte: transcript.first_byte.map(|at| at - start),
+            duration: start.elapsed(),
+            events: transcript.events,
+        })
+    }
+
+    /// Execute command like `execute`, handing out the lines of output as they arrive.
+    /// The stream ends at the prompt.
+    ///
+    /// If the stream is dropped before it ends, the rest of the output is left unread and
+    /// would be taken for the output of the next command: read up to the prompt first, with
+    /// `expect` for instance.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # async fn run(mut telnet: mini_telnet::Telnet) -> Result<(), mini_telnet::error::TelnetError> {
+    /// use futures::{pin_mut, StreamExt};
+    ///
+    /// let lines = telnet.execute_stream("show tech-support");
+    /// pin_mut!(lines);
+    /// while let Some(line) = lines.next().await {
+    ///     print!("{}", line?);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    pub fn execute_stream(
+        &mut self,
+        cmd: &str,
+    ) -> impl Stream<Item = Result<String, TelnetError>> + '_ {
+        let command = Telnet::format_enter_str(cmd);
+        let filter = OutputFilter::new(&command, self.server_echo());
+
+        stream::try_unfold(
+            (self, filter, Some(command)),
+            |(telnet, mut filter, command)| async move {
+                if let Some(command) = command {
+                    let command = telnet.charsets.encode(&command)?;
+                    telnet.write(Message::Data(command)).await?;
+                }
+                loop {
+                    let line = telnet.next_line().await?;
+                    match filter.feed(line, &telnet.prompts, &telnet.pagers) {
+                        Step::Prompt(_) => return Ok(None),
+                        Step::Pager(response) => telnet.write(Message::Data(response)).await?,
+                        Step::Line(line) => {
+                            let line = telnet.charsets.decode(&ansi::render(&line))?;
+                            return Ok(Some((line, (telnet, filter, None))));
                         }
+                        Step::Skip => {}
                     }
-                    None => return Err(TelnetError::NoMoreData),
-                },
-                Err(_) => return Err(TelnetError::Timeout("read next framed".to_string())),
-            }
-        }
-        let result = self.content.join("");
-        self.content.clear();
-        Ok(result)
+                }
+            },
+        )
     }
 
     /// All echoed content is returned when the command is executed.(**Note** that this may contain some
@@ -270,101 +617,419 @@ impl Telnet {
     /// # Examples
     ///
     /// ```no_run
+    /// # async fn run(mut telnet: mini_telnet::Telnet) -> Result<(), mini_telnet::error::TelnetError> {
     /// assert_eq!(
     ///     "echo 'haha'\nhaha\n",
     ///     telnet.normal_execute("echo 'haha'").await?
     /// );
+    /// # Ok(())
+    /// # }
     ///```
     ///
     pub async fn normal_execute(&mut self, cmd: &str) -> Result<String, TelnetError> {
         let command = Telnet::format_enter_str(cmd);
-        let mut incomplete_line: Vec<u8> = vec![];
+        let (lines, _) = self
+            .run_command(&command, OutputFilter::unfiltered())
+            .await?;
+        for line in lines {
+            self.content
+                .push(self.charsets.decode(&ansi::render(&line))?);
+        }
+        let result = self.content.join("");
+        self.content.clear();
+        Ok(result)
+    }
 
-        let (read, mut write) = self.stream.split();
-        match time::timeout(self.timeout, write.write(command.as_bytes())).await {
+    /// Execute command like `normal_execute`, returning the bytes as received, echo included.
+    /// Escape sequences are kept unless `TelnetBuilder::strip_escapes` is set.
+    pub async fn normal_execute_bytes(&mut self, cmd: &str) -> Result<Vec<u8>, TelnetError> {
+        let command = Telnet::format_enter_str(cmd);
+        let (lines, _) = self
+            .run_command(&command, OutputFilter::unfiltered())
+            .await?;
+        Ok(self.join_bytes(lines))
+    }
+
+    // Send `command`, then collect its output as received and the prompt line.
+    async fn run_command(
+        &mut self,
+        command: &str,
+        mut filter: OutputFilter,
+    ) -> Result<(Vec<Vec<u8>>, Vec<u8>), TelnetError> {
+        let data = self.charsets.encode(command)?;
+        match time::timeout(self.timeout, self.stream.send(Message::Data(data))).await {
             Ok(res) => res?,
             Err(_) => return Err(TelnetError::Timeout("write cmd".to_string())),
         };
-        let mut telnet = FramedRead::new(read, TelnetCodec::default());
+
+        let mut lines = vec![];
+        loop {
+            let line = self.next_line().await?;
+            match filter.feed(line, &self.prompts, &self.pagers) {
+                Step::Prompt(prompt) => return Ok((lines, prompt)),
+                Step::Line(line) => lines.push(line),
+                Step::Pager(response) => self.write(Message::Data(response)).await?,
+                Step::Skip => {}
+            }
+        }
+    }
+
+    // The output of the `_bytes` variants.
+    fn join_bytes(&self, lines: Vec<Vec<u8>>) -> Vec<u8> {
+        let bytes = lines.concat();
+        if self.strip_escapes {
+            ansi::strip_sequences(&bytes)
+        } else {
+            bytes
+        }
+    }
+
+    /// Read until one of `patterns` shows up, the earliest match wins.
+    /// Output after the match is kept for the next read.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # async fn run(mut telnet: mini_telnet::Telnet) -> Result<(), mini_telnet::error::TelnetError> {
+    /// use mini_telnet::expect::Pattern;
+    ///
+    /// telnet.normal_execute("copy running-config startup-config").await?;
+    /// let m = telnet
+    ///     .expect(&[
+    ///         Pattern::literal("[confirm]"),
+    ///         Pattern::regex(r"Destination filename \[(\S+)\]\?")?,
+    ///     ])
+    ///     .await?;
+    /// if m.index == 1 {
+    ///     println!("saving to {:?}", m.captures[0]);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    pub async fn expect(&mut self, patterns: &[Pattern]) -> Result<Match, TelnetError> {
+        // The output as received, the same stripped of escape sequences and where the
+        // byte behind each stripped byte ends in `raw`.
+        let mut raw: Vec<u8> = vec![];
+        let mut text: Vec<u8> = vec![];
+        let mut ends: Vec<usize> = vec![];
+        let mut parser = ansi::Parser::default();
 
         loop {
-            match time::timeout(self.timeout, telnet.next()).await {
+            match time::timeout(self.timeout, self.next_item()).await {
                 Ok(res) => match res {
-                    Some(item) => {
-                        if let Item::Line(line) = item? {
-                            let mut line = self.clear.color(&line);
-                            if self
-                                .prompts
-                                .iter()
-                                .filter(|p| line.ends_with(p.as_bytes()))
-                                .count()
-                                != 0
-                            {
-                                break;
+                    Some(item) => match item? {
+                        Item::Line(line) => {
+                            for byte in line {
+                                raw.push(byte);
+                                if let Some(b) = ansi::kept(parser.advance(byte)) {
+                                    text.push(b);
+                                    ends.push(raw.len());
+                                }
                             }
 
-                            if !line.ends_with(&[10]) || !incomplete_line.is_empty() {
-                                incomplete_line.append(&mut line);
-                            } else {
-                                self.content.push(decode(&line)?);
-                                continue;
-                            }
-                            // ignore command line
-                            if self
-                                .prompts
+                            let found = patterns
                                 .iter()
-                                .filter(|p| incomplete_line.ends_with(p.as_bytes()))
-                                .count()
-                                != 0
-                            {
-                                break;
-                            }
-                            if incomplete_line.ends_with(&[10]) {
-                                self.content.push(decode(&incomplete_line)?);
-                                incomplete_line.clear();
+                                .enumerate()
+                                .filter_map(|(i, p)| p.find(&text).map(|m| (i, m)))
+                                .min_by_key(|(_, found)| found.start);
+                            if let Some((index, found)) = found {
+                                // What follows the match is kept as received.
+                                let end = found.end.checked_sub(1).map_or(0, |i| ends[i]);
+                                self.pending = raw.split_off(end);
+                                let captures = found
+                                    .groups
+                                    .iter()
+                                    .map(|g| {
+                                        g.as_deref().map(|g| self.charsets.decode(g)).transpose()
+                                    })
+                                    .collect::<Result<Vec<_>, _>>()?;
+                                return Ok(Match {
+                                    index,
+                                    before: self.charsets.decode(&text[..found.start])?,
+                                    matched: self.charsets.decode(&text[found.start..found.end])?,
+                                    captures,
+                                });
                             }
                         }
-                    }
+                        item => negotiate(&mut self.negotiation, &item, &mut self.stream).await?,
+                    },
                     None => return Err(TelnetError::NoMoreData),
                 },
-                Err(_) => return Err(TelnetError::Timeout("read next framed".to_string())),
+                Err(_) => return Err(TelnetError::Timeout("expect".to_string())),
             }
         }
-        let result = self.content.join("");
-        self.content.clear();
-        Ok(result)
     }
-}
 
-fn decode(line: &[u8]) -> Result<String, TelnetError> {
-    match String::from_utf8(line.to_vec()) {
-        Ok(result) => Ok(result),
-        Err(e) => {
-            if let Ok(result) = GBK.decode(line, DecoderTrap::Strict) {
-                return Ok(result);
+    /// Send `line` followed by `\n` without waiting for anything.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # async fn run(mut telnet: mini_telnet::Telnet) -> Result<(), mini_telnet::error::TelnetError> {
+    /// use mini_telnet::expect::Pattern;
+    ///
+    /// telnet.send_line("reload").await?;
+    /// telnet.expect(&[Pattern::literal("[confirm]")]).await?;
+    /// telnet.send_line("").await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    pub async fn send_line(&mut self, line: &str) -> Result<(), TelnetError> {
+        let line = self.charsets.encode(&Telnet::format_enter_str(line))?;
+        self.write(Message::Data(line)).await
+    }
+
+    /// Send raw bytes without waiting for anything, `0xff` bytes are escaped as `IAC IAC`.
+    pub async fn send_bytes(&mut self, bytes: &[u8]) -> Result<(), TelnetError> {
+        self.write(Message::Data(bytes.to_vec())).await
+    }
+
+    /// Send the control character `Ctrl-<c>`, such as `'c'` for Ctrl-C or `']'` for Ctrl-],
+    /// without waiting for anything. `'?'` sends DEL.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # async fn run(mut telnet: mini_telnet::Telnet) -> Result<(), mini_telnet::error::TelnetError> {
+    /// telnet.send_line("ping 10.0.0.1").await?;
+    /// telnet.send_control('c').await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    pub async fn send_control(&mut self, c: char) -> Result<(), TelnetError> {
+        let byte = match c.to_ascii_uppercase() {
+            upper @ '@'..='_' => upper as u8 & 0x1f,
+            '?' => 0x7f,
+            _ => return Err(TelnetError::InvalidControl(c)),
+        };
+        self.write(Message::Data(vec![byte])).await
+    }
+
+    /// Send `IAC IP` followed by a Synch (`IAC DM` with the TCP Urgent mark on `DM`),
+    /// so the server interrupts the running process and drops the output queued so far.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # async fn run(mut telnet: mini_telnet::Telnet) -> Result<(), mini_telnet::error::TelnetError> {
+    /// telnet.send_line("ping 10.0.0.1 repeat 100000").await?;
+    /// telnet.interrupt().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    pub async fn interrupt(&mut self) -> Result<(), TelnetError> {
+        self.write(Message::Command(IP)).await?;
+        let synch = async {
+            self.stream.get_mut().write_all(&[IAC]).await?;
+            let tcp = self.stream.get_ref();
+            loop {
+                tcp.writable().await?;
+                match tcp.try_io(Interest::WRITABLE, || {
+                    SockRef::from(tcp).send_out_of_band(&[DM])
+                }) {
+                    Ok(_) => return Ok(()),
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(TelnetError::IOError(e)),
+                }
             }
+        };
+        match time::timeout(self.timeout, synch).await {
+            Ok(res) => res,
+            Err(_) => Err(TelnetError::Timeout("interrupt".to_string())),
+        }
+    }
+
+    /// Send `IAC AO`, asking the server to discard the output of the running process.
+    pub async fn abort_output(&mut self) -> Result<(), TelnetError> {
+        self.write(Message::Command(AO)).await
+    }
+
+    /// Send `IAC BRK`.
+    pub async fn send_break(&mut self) -> Result<(), TelnetError> {
+        self.write(Message::Command(BRK)).await
+    }
 
-            if let Ok(result) = GB18030.decode(line, DecoderTrap::Strict) {
-                return Ok(result);
+    /// Send `IAC AYT`, then wait for the server to send any data within the timeout.
+    /// Returns `false` if nothing came back in time. The answer is kept for the next read,
+    /// as it cannot be told apart from command output.
+    pub async fn are_you_there(&mut self) -> Result<bool, TelnetError> {
+        self.write(Message::Command(AYT)).await?;
+        let timeout = self.timeout;
+        let answer = async {
+            loop {
+                match self.next_framed().await {
+                    Some(item) => match item? {
+                        Item::Line(line) => {
+                            self.pending.extend_from_slice(&line);
+                            return Ok(true);
+                        }
+                        item => negotiate(&mut self.negotiation, &item, &mut self.stream).await?,
+                    },
+                    None => return Err(TelnetError::NoMoreData),
+                }
             }
-            Err(TelnetError::ParseError(e))
+        };
+        match time::timeout(timeout, answer).await {
+            Ok(res) => res,
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// The character set agreed on through CHARSET, see `TelnetBuilder::charsets`.
+    pub fn charset(&self) -> Option<String> {
+        self.charsets.negotiated_name()
+    }
+
+    /// The emulated screen, `None` unless enabled with `TelnetBuilder::screen`.
+    pub fn screen(&self) -> Option<&Screen> {
+        self.screen.as_ref()
+    }
+
+    /// Read until `predicate` holds for the emulated screen, it is checked before reading
+    /// anything and after every read. The timeout applies to the whole wait.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use mini_telnet::Telnet;
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut telnet = Telnet::builder()
+    ///     .terminal_types(&["VT100"])
+    ///     .screen(80, 24)
+    ///     .connect("192.168.0.1:23")
+    ///     .await?;
+    ///
+    /// telnet.wait_for_screen(|s| s.contents().contains("Main Menu")).await?;
+    /// telnet.send_bytes(b"2").await?;
+    /// // Wait for the battery status page to be drawn, with the cursor back on the input line.
+    /// telnet
+    ///     .wait_for_screen(|s| s.contents().contains("Battery Status") && s.cursor().0 == 23)
+    ///     .await?;
+    /// println!("{}", telnet.screen().unwrap().contents());
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    pub async fn wait_for_screen<F>(&mut self, mut predicate: F) -> Result<(), TelnetError>
+    where
+        F: FnMut(&Screen) -> bool,
+    {
+        let timeout = self.timeout;
+        let wait = async {
+            loop {
+                match &self.screen {
+                    Some(screen) if predicate(screen) => return Ok(()),
+                    Some(_) => {}
+                    None => return Err(TelnetError::NoScreen),
+                }
+                self.next_line().await?;
+            }
+        };
+        match time::timeout(timeout, wait).await {
+            Ok(res) => res,
+            Err(_) => Err(TelnetError::Timeout("wait for screen".to_string())),
+        }
+    }
+
+    // Send `msg` to the server.
+    async fn write(&mut self, msg: Message) -> Result<(), TelnetError> {
+        match time::timeout(self.timeout, self.stream.send(msg)).await {
+            Ok(res) => res,
+            Err(_) => Err(TelnetError::Timeout("write".to_string())),
+        }
+    }
+
+    /// Change the window size, the new size is sent right away if the server enabled NAWS.
+    /// The emulated screen, if any, is resized as well.
+    /// This replaces a custom NAWS handler registered with `TelnetBuilder::option_handler`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # async fn run(mut telnet: mini_telnet::Telnet) -> Result<(), mini_telnet::error::TelnetError> {
+    /// telnet.resize(200, 50).await?;
+    /// let routes = telnet.execute("show ip route").await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    pub async fn resize(&mut self, cols: u16, rows: u16) -> Result<(), TelnetError> {
+        let naws = Naws::new(cols, rows);
+        let payload = naws.payload();
+        self.negotiation.register(Box::new(naws));
+        if let Some(screen) = &mut self.screen {
+            screen.resize(cols, rows);
+        }
+        if !self.negotiation.local_enabled(NAWS) {
+            return Ok(());
+        }
+
+        match time::timeout(
+            self.timeout,
+            self.stream.send(Message::Subnegotiation(NAWS, payload)),
+        )
+        .await
+        {
+            Ok(res) => res,
+            Err(_) => Err(TelnetError::Timeout("resize".to_string())),
         }
     }
 }
 
-struct Clear {
-    color_re: Regex,
+// Answer option negotiation and subnegotiation.
+async fn negotiate<S>(
+    negotiation: &mut Negotiation,
+    item: &Item,
+    write: &mut S,
+) -> Result<(), TelnetError>
+where
+    S: Sink<Message, Error = TelnetError> + Unpin,
+{
+    for msg in negotiation.receive(item) {
+        write.feed(msg).await?;
+    }
+    write.flush().await
 }
 
-impl Clear {
-    pub fn new() -> Result<Self, TelnetError> {
-        let color_re = Regex::new(r"\[\d{2,3}m")?;
-        Ok(Self { color_re })
+// `cmd` followed by the echo of `marker` and its exit status, `None` when `cmd` is blank.
+fn status_command(cmd: &str, marker: &str) -> Option<String> {
+    let cmd = cmd.trim_end();
+    if cmd.is_empty() {
+        return None;
     }
+    let separator = if cmd.ends_with(['&', ';']) { " " } else { "; " };
+    Some(format!("{}{}echo {}$?", cmd, separator, marker))
+}
+
+// A marker unlikely to show up in any output, the exit status is printed right after it.
+fn status_marker() -> String {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    format!(
+        "__MT_STATUS_{:x}_{:x}__",
+        nanos,
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    pub fn color(&self, content: &[u8]) -> Vec<u8> {
-        self.color_re
-            .replace_all(content, &[] as &[u8])
-            .into_owned()
+    #[test]
+    fn status_command_separator() {
+        assert_eq!(status_command("ls", "M").unwrap(), "ls; echo M$?");
+        assert_eq!(
+            status_command("sleep 9 &", "M").unwrap(),
+            "sleep 9 & echo M$?"
+        );
+        assert_eq!(status_command("true;\n", "M").unwrap(), "true; echo M$?");
+        assert_eq!(status_command(" \n", "M"), None);
     }
 }
@@ -0,0 +1,400 @@
+//! Option negotiation following the Q method of RFC 1143.
+use std::{collections::HashMap, fmt};
+
+use crate::codec::{Item, Message};
+use crate::option::OptionHandler;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum State {
+    #[default]
+    No,
+    Yes,
+    WantNo,
+    WantYes,
+}
+
+/// State of one side (us or him) of one option.
+#[derive(Debug, Clone, Copy, Default)]
+struct Side {
+    state: State,
+    // The `OPPOSITE` queue bit, set when the reverse was requested while negotiating.
+    opposite: bool,
+}
+
+impl Side {
+    /// The peer sent `WILL` (for him) or `DO` (for us).
+    /// Returns `Some(true)` to answer positively, `Some(false)` to refuse.
+    fn enable(&mut self, accept: bool) -> Option<bool> {
+        match (self.state, self.opposite) {
+            (State::No, _) => {
+                if accept {
+                    self.state = State::Yes;
+                    Some(true)
+                } else {
+                    Some(false)
+                }
+            }
+            (State::Yes, _) => None,
+            // Our refusal was answered by an enable, the peer is misbehaving.
+            (State::WantNo, false) => {
+                self.state = State::No;
+                None
+            }
+            (State::WantNo, true) => {
+                self.state = State::Yes;
+                self.opposite = false;
+                None
+            }
+            (State::WantYes, false) => {
+                self.state = State::Yes;
+                None
+            }
+            (State::WantYes, true) => {
+                self.state = State::WantNo;
+                self.opposite = false;
+                Some(false)
+            }
+        }
+    }
+
+    /// The peer sent `WONT` (for him) or `DONT` (for us).
+    fn disable(&mut self) -> Option<bool> {
+        match (self.state, self.opposite) {
+            (State::No, _) => None,
+            (State::Yes, _) => {
+                self.state = State::No;
+                Some(false)
+            }
+            (State::WantNo, false) => {
+                self.state = State::No;
+                None
+            }
+            (State::WantNo, true) => {
+                self.state = State::WantYes;
+                self.opposite = false;
+                Some(true)
+            }
+            (State::WantYes, _) => {
+                self.state = State::No;
+                self.opposite = false;
+                None
+            }
+        }
+    }
+
+    /// We want the option enabled.
+    fn request_enable(&mut self) -> Option<bool> {
+        match (self.state, self.opposite) {
+            (State::No, _) => {
+                self.state = State::WantYes;
+                Some(true)
+            }
+            (State::WantNo, false) => {
+                self.opposite = true;
+                None
+            }
+            (State::WantYes, true) => {
+                self.opposite = false;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.state == State::Yes
+    }
+}
+
+/// Per option negotiation state of both sides of the connection.
+#[derive(Default)]
+pub(crate) struct Negotiation {
+    handlers: HashMap<u8, Box<dyn OptionHandler>>,
+    local: HashMap<u8, Side>,
+    remote: HashMap<u8, Side>,
+}
+
+impl fmt::Debug for Negotiation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Negotiation")
+            .field("handlers", &self.handlers.values())
+            .field("local", &self.local)
+            .field("remote", &self.remote)
+            .finish()
+    }
+}
+
+impl Negotiation {
+    /// Register `handler`, replacing the one already registered for the same option.
+    pub(crate) fn register(&mut self, handler: Box<dyn OptionHandler>) {
+        self.handlers.insert(handler.option(), handler);
+    }
+
+    /// Requests for the options the handlers offer or ask for up front.
+    pub(crate) fn start(&mut self) -> Vec<Message> {
+        let mut offers: Vec<u8> = vec![];
+        let mut requests: Vec<u8> = vec![];
+        for (opt, handler) in self.handlers.iter() {
+            if handler.offer_local() {
+                offers.push(*opt);
+            }
+            if handler.request_remote() {
+                requests.push(*opt);
+            }
+        }
+        offers.sort_unstable();
+        requests.sort_unstable();
+
+        let mut msgs = vec![];
+        for opt in offers {
+            msgs.extend(self.enable_local(opt));
+        }
+        for opt in requests {
+            msgs.extend(self.enable_remote(opt));
+        }
+        msgs
+    }
+
+    /// Handle negotiation and subnegotiation from the server, returning the replies to send.
+    pub(crate) fn receive(&mut self, item: &Item) -> Vec<Message> {
+        let mut msgs = vec![];
+        match *item {
+            Item::Will(opt) => {
+                let accept = self
+                    .handlers
+                    .get_mut(&opt)
+                    .is_some_and(|h| h.accept_remote());
+                let side = self.remote.entry(opt).or_default();
+                let enabled = side.enabled();
+                msgs.extend(side.enable(accept).map(|r| remote_message(r, opt)));
+                self.remote_changed(opt, enabled, &mut msgs);
+            }
+            Item::Wont(opt) => {
+                let side = self.remote.entry(opt).or_default();
+                let enabled = side.enabled();
+                msgs.extend(side.disable().map(|r| remote_message(r, opt)));
+                self.remote_changed(opt, enabled, &mut msgs);
+            }
+            Item::Do(opt) => {
+                let accept = self
+                    .handlers
+                    .get_mut(&opt)
+                    .is_some_and(|h| h.accept_local());
+                let side = self.local.entry(opt).or_default();
+                let enabled = side.enabled();
+                msgs.extend(side.enable(accept).map(|r| local_message(r, opt)));
+                self.local_changed(opt, enabled, &mut msgs);
+            }
+            Item::Dont(opt) => {
+                let side = self.local.entry(opt).or_default();
+                let enabled = side.enabled();
+                msgs.extend(side.disable().map(|r| local_message(r, opt)));
+                self.local_changed(opt, enabled, &mut msgs);
+            }
+            Item::Subnegotiation(opt, ref payload) => {
+                if let Some(reply) = self
+                    .handlers
+                    .get_mut(&opt)
+                    .and_then(|h| h.subnegotiation(payload))
+                {
+                    msgs.push(Message::Subnegotiation(opt, reply));
+                }
+            }
+            _ => {}
+        }
+        msgs
+    }
+
+    /// Ask to enable `opt` on our side, options without a handler are never asked for.
+    pub(crate) fn enable_local(&mut self, opt: u8) -> Option<Message> {
+        if !self.handlers.contains_key(&opt) {
+            return None;
+        }
+        let reply = self.local.entry(opt).or_default().request_enable()?;
+        Some(local_message(reply, opt))
+    }
+
+    /// Ask the server to enable `opt`, options without a handler are never asked for.
+    pub(crate) fn enable_remote(&mut self, opt: u8) -> Option<Message> {
+        if !self.handlers.contains_key(&opt) {
+            return None;
+        }
+        let reply = self.remote.entry(opt).or_default().request_enable()?;
+        Some(remote_message(reply, opt))
+    }
+
+    /// Whether `opt` is enabled on our side.
+    pub(crate) fn local_enabled(&self, opt: u8) -> bool {
+        self.local.get(&opt).is_some_and(Side::enabled)
+    }
+
+    /// Whether `opt` is enabled on the server side.
+    pub(crate) fn remote_enabled(&self, opt: u8) -> bool {
+        self.remote.get(&opt).is_some_and(Side::enabled)
+    }
+
+    // Tell the handler when our side of `opt` flipped.
+    fn local_changed(&mut self, opt: u8, was_enabled: bool, msgs: &mut Vec<Message>) {
+        let enabled = self.local_enabled(opt);
+        if enabled == was_enabled {
+            return;
+        }
+        if let Some(payload) = self
+            .handlers
+            .get_mut(&opt)
+            .and_then(|h| h.local_changed(enabled))
+        {
+            msgs.push(Message::Subnegotiation(opt, payload));
+        }
+    }
+
+    // Tell the handler when the server side of `opt` flipped.
+    fn remote_changed(&mut self, opt: u8, was_enabled: bool, msgs: &mut Vec<Message>) {
+        let enabled = self.remote_enabled(opt);
+        if enabled == was_enabled {
+            return;
+        }
+        if let Some(payload) = self
+            .handlers
+            .get_mut(&opt)
+            .and_then(|h| h.remote_changed(enabled))
+        {
+            msgs.push(Message::Subnegotiation(opt, payload));
+        }
+    }
+}
+
+fn local_message(enable: bool, opt: u8) -> Message {
+    if enable {
+        Message::Will(opt)
+    } else {
+        Message::Wont(opt)
+    }
+}
+
+fn remote_message(enable: bool, opt: u8) -> Message {
+    if enable {
+        Message::Do(opt)
+    } else {
+        Message::Dont(opt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::option::Accept;
+
+    const ECHO: u8 = 1;
+    const SGA: u8 = 3;
+
+    #[derive(Debug)]
+    struct Eager(u8);
+
+    impl OptionHandler for Eager {
+        fn option(&self) -> u8 {
+            self.0
+        }
+
+        fn offer_local(&self) -> bool {
+            true
+        }
+
+        fn request_remote(&self) -> bool {
+            true
+        }
+    }
+
+    fn side(state: State, opposite: bool) -> Side {
+        Side { state, opposite }
+    }
+
+    #[test]
+    fn enable_while_negotiating() {
+        // (state, opposite) -> reply, new state
+        let cases = [
+            (State::WantYes, false, None, State::Yes),
+            (State::WantYes, true, Some(false), State::WantNo),
+            (State::WantNo, false, None, State::No),
+            (State::WantNo, true, None, State::Yes),
+        ];
+        for (state, opposite, reply, next) in cases {
+            let mut s = side(state, opposite);
+            assert_eq!(s.enable(true), reply, "{:?} {}", state, opposite);
+            assert_eq!(s.state, next, "{:?} {}", state, opposite);
+            assert!(!s.opposite);
+        }
+    }
+
+    #[test]
+    fn disable_while_negotiating() {
+        let cases = [
+            (State::WantYes, false, None, State::No),
+            (State::WantYes, true, None, State::No),
+            (State::WantNo, false, None, State::No),
+            (State::WantNo, true, Some(true), State::WantYes),
+        ];
+        for (state, opposite, reply, next) in cases {
+            let mut s = side(state, opposite);
+            assert_eq!(s.disable(), reply, "{:?} {}", state, opposite);
+            assert_eq!(s.state, next, "{:?} {}", state, opposite);
+            assert!(!s.opposite);
+        }
+    }
+
+    #[test]
+    fn request_enable_while_negotiating() {
+        let mut s = side(State::WantNo, false);
+        assert_eq!(s.request_enable(), None);
+        assert!(s.opposite);
+        // Asked again, nothing more is queued.
+        assert_eq!(s.request_enable(), None);
+        assert!(s.opposite);
+
+        let mut s = side(State::WantYes, true);
+        assert_eq!(s.request_enable(), None);
+        assert_eq!((s.state, s.opposite), (State::WantYes, false));
+    }
+
+    #[test]
+    fn options_without_handler_are_refused() {
+        let mut negotiation = Negotiation::default();
+        assert!(negotiation.start().is_empty());
+        assert_eq!(
+            negotiation.receive(&Item::Will(ECHO)),
+            vec![Message::Dont(ECHO)]
+        );
+        assert_eq!(
+            negotiation.receive(&Item::Do(ECHO)),
+            vec![Message::Wont(ECHO)]
+        );
+        assert_eq!(negotiation.enable_local(ECHO), None);
+        assert_eq!(negotiation.enable_remote(ECHO), None);
+        assert!(!negotiation.local_enabled(ECHO));
+        assert!(!negotiation.remote_enabled(ECHO));
+    }
+
+    #[test]
+    fn handlers_accept_and_request() {
+        let mut negotiation = Negotiation::default();
+        negotiation.register(Box::new(Accept::new(ECHO, false, true)));
+        negotiation.register(Box::new(Eager(SGA)));
+        assert_eq!(
+            negotiation.start(),
+            vec![Message::Will(SGA), Message::Do(SGA)]
+        );
+        assert!(negotiation.receive(&Item::Do(SGA)).is_empty());
+        assert!(negotiation.receive(&Item::Will(SGA)).is_empty());
+        assert!(negotiation.local_enabled(SGA) && negotiation.remote_enabled(SGA));
+
+        assert_eq!(
+            negotiation.receive(&Item::Will(ECHO)),
+            vec![Message::Do(ECHO)]
+        );
+        assert_eq!(
+            negotiation.receive(&Item::Do(ECHO)),
+            vec![Message::Wont(ECHO)]
+        );
+        assert!(negotiation.remote_enabled(ECHO) && !negotiation.local_enabled(ECHO));
+    }
+}
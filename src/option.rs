@@ -0,0 +1,620 @@
+//! Telnet option handlers.
+use std::fmt;
+
+use crate::charset::{by_name, EncodingRef, Negotiated};
+
+/// Binary transmission option (RFC 856).
+pub const BINARY: u8 = 0;
+/// Echo option (RFC 857).
+pub const ECHO: u8 = 1;
+/// Suppress go ahead option (RFC 858).
+pub const SGA: u8 = 3;
+/// Terminal type option (RFC 1091).
+pub const TTYPE: u8 = 24;
+/// Negotiate About Window Size option (RFC 1073).
+pub const NAWS: u8 = 31;
+/// New environment option (RFC 1572).
+pub const NEW_ENVIRON: u8 = 39;
+/// Character set option (RFC 2066).
+pub const CHARSET: u8 = 42;
+
+// TTYPE subnegotiation commands.
+const TTYPE_IS: u8 = 0;
+const TTYPE_SEND: u8 = 1;
+
+// NEW-ENVIRON subnegotiation commands and type codes.
+const ENV_IS: u8 = 0;
+const ENV_SEND: u8 = 1;
+const ENV_VAR: u8 = 0;
+const ENV_VALUE: u8 = 1;
+const ENV_ESC: u8 = 2;
+const ENV_USERVAR: u8 = 3;
+
+// CHARSET subnegotiation commands.
+const CHARSET_REQUEST: u8 = 1;
+const CHARSET_ACCEPTED: u8 = 2;
+const CHARSET_REJECTED: u8 = 3;
+const CHARSET_TTABLE_IS: u8 = 4;
+const CHARSET_TTABLE_REJECTED: u8 = 5;
+
+/// Decides how one telnet option is negotiated, and answers its subnegotiations.
+///
+/// Register it with `TelnetBuilder::option_handler`, a handler replaces the built-in one
+/// for the same option code. Options without a handler are always refused.
+///
+/// The methods returning `Option<Vec<u8>>` may hand back a subnegotiation payload, which
+/// is sent as `IAC SB <option> <payload> IAC SE`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use mini_telnet::{option::OptionHandler, Telnet};
+///
+/// // Let the server enable vendor option 200, and answer its status query.
+/// #[derive(Debug)]
+/// struct Vendor;
+///
+/// impl OptionHandler for Vendor {
+///     fn option(&self) -> u8 {
+///         200
+///     }
+///
+///     fn accept_remote(&mut self) -> bool {
+///         true
+///     }
+///
+///     fn subnegotiation(&mut self, payload: &[u8]) -> Option<Vec<u8>> {
+///         (payload == [1]).then(|| b"\x00ready".to_vec())
+///     }
+/// }
+///
+/// let builder = Telnet::builder().option_handler(Vendor);
+/// ```
+pub trait OptionHandler: fmt::Debug + Send {
+    /// The option code this handler is responsible for.
+    fn option(&self) -> u8;
+
+    /// Whether to enable the option on our side when the server sends `DO`.
+    fn accept_local(&mut self) -> bool {
+        false
+    }
+
+    /// Whether to let the server enable the option when it sends `WILL`.
+    fn accept_remote(&mut self) -> bool {
+        false
+    }
+
+    /// Whether to offer the option with `WILL` as soon as the connection is up.
+    fn offer_local(&self) -> bool {
+        false
+    }
+
+    /// Whether to ask the server for the option with `DO` as soon as the connection is up.
+    fn request_remote(&self) -> bool {
+        false
+    }
+
+    /// Called when the option is enabled or disabled on our side.
+    fn local_changed(&mut self, _enabled: bool) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Called when the option is enabled or disabled on the server side.
+    fn remote_changed(&mut self, _enabled: bool) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Called with the payload of every subnegotiation of the option.
+    fn subnegotiation(&mut self, _payload: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Agrees to an option without any subnegotiation.
+#[derive(Debug)]
+pub(crate) struct Accept {
+    option: u8,
+    local: bool,
+    remote: bool,
+}
+
+impl Accept {
+    /// Accept `option` on our side when `local`, and on the server side when `remote`.
+    pub(crate) fn new(option: u8, local: bool, remote: bool) -> Self {
+        Accept {
+            option,
+            local,
+            remote,
+        }
+    }
+}
+
+impl OptionHandler for Accept {
+    fn option(&self) -> u8 {
+        self.option
+    }
+
+    fn accept_local(&mut self) -> bool {
+        self.local
+    }
+
+    fn accept_remote(&mut self) -> bool {
+        self.remote
+    }
+}
+
+/// Reports the window size once NAWS is enabled.
+#[derive(Debug)]
+pub(crate) struct Naws {
+    cols: u16,
+    rows: u16,
+}
+
+impl Naws {
+    pub(crate) fn new(cols: u16, rows: u16) -> Self {
+        Naws { cols, rows }
+    }
+
+    pub(crate) fn payload(&self) -> Vec<u8> {
+        let mut payload = self.cols.to_be_bytes().to_vec();
+        payload.extend_from_slice(&self.rows.to_be_bytes());
+        payload
+    }
+}
+
+impl OptionHandler for Naws {
+    fn option(&self) -> u8 {
+        NAWS
+    }
+
+    fn accept_local(&mut self) -> bool {
+        true
+    }
+
+    fn local_changed(&mut self, enabled: bool) -> Option<Vec<u8>> {
+        enabled.then(|| self.payload())
+    }
+}
+
+/// Answers `TTYPE SEND` with the configured terminal types.
+///
+/// Every request gets the next type of the list, the last one is sent twice to mark the end
+/// of the list, then it starts over from the first one (RFC 1091).
+#[derive(Debug)]
+pub(crate) struct TerminalType {
+    types: Vec<String>,
+    next: usize,
+}
+
+impl TerminalType {
+    pub(crate) fn new(types: Vec<String>) -> Self {
+        TerminalType { types, next: 0 }
+    }
+}
+
+impl OptionHandler for TerminalType {
+    fn option(&self) -> u8 {
+        TTYPE
+    }
+
+    fn accept_local(&mut self) -> bool {
+        !self.types.is_empty()
+    }
+
+    fn local_changed(&mut self, _enabled: bool) -> Option<Vec<u8>> {
+        self.next = 0;
+        None
+    }
+
+    fn subnegotiation(&mut self, payload: &[u8]) -> Option<Vec<u8>> {
+        if payload != [TTYPE_SEND] || self.types.is_empty() {
+            return None;
+        }
+        let last = self.types.len() - 1;
+        let name = &self.types[self.next.min(last)];
+        self.next = (self.next + 1) % (self.types.len() + 1);
+
+        let mut reply = vec![TTYPE_IS];
+        reply.extend_from_slice(name.as_bytes());
+        Some(reply)
+    }
+}
+
+/// A variable sent through NEW-ENVIRON, its value is never printed by `Debug`.
+pub(crate) struct EnvVar {
+    user: bool,
+    name: String,
+    value: String,
+}
+
+impl EnvVar {
+    /// A well-known variable such as `USER` or `DISPLAY`.
+    pub(crate) fn var(name: String, value: String) -> Self {
+        EnvVar {
+            user: false,
+            name,
+            value,
+        }
+    }
+
+    /// A user defined variable.
+    pub(crate) fn user_var(name: String, value: String) -> Self {
+        EnvVar {
+            user: true,
+            name,
+            value,
+        }
+    }
+
+    fn kind(&self) -> u8 {
+        if self.user {
+            ENV_USERVAR
+        } else {
+            ENV_VAR
+        }
+    }
+}
+
+impl fmt::Debug for EnvVar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EnvVar")
+            .field("user", &self.user)
+            .field("name", &self.name)
+            .field("value", &"***")
+            .finish()
+    }
+}
+
+/// Answers `NEW-ENVIRON SEND` with the configured variables.
+#[derive(Debug)]
+pub(crate) struct NewEnviron {
+    vars: Vec<EnvVar>,
+}
+
+impl NewEnviron {
+    pub(crate) fn new(vars: Vec<EnvVar>) -> Self {
+        NewEnviron { vars }
+    }
+}
+
+impl OptionHandler for NewEnviron {
+    fn option(&self) -> u8 {
+        NEW_ENVIRON
+    }
+
+    fn accept_local(&mut self) -> bool {
+        !self.vars.is_empty()
+    }
+
+    fn subnegotiation(&mut self, payload: &[u8]) -> Option<Vec<u8>> {
+        let (&cmd, list) = payload.split_first()?;
+        if cmd != ENV_SEND {
+            return None;
+        }
+
+        // The requested `(type, name)` pairs, an empty name asks for every variable of the type.
+        let mut requests: Vec<(u8, Vec<u8>)> = vec![];
+        let mut bytes = list.iter().copied();
+        while let Some(byte) = bytes.next() {
+            match byte {
+                ENV_VAR | ENV_USERVAR => requests.push((byte, vec![])),
+                ENV_ESC => {
+                    if let (Some(next), Some((_, name))) = (bytes.next(), requests.last_mut()) {
+                        name.push(next);
+                    }
+                }
+                _ => {
+                    if let Some((_, name)) = requests.last_mut() {
+                        name.push(byte);
+                    }
+                }
+            }
+        }
+        if requests.is_empty() {
+            requests = vec![(ENV_VAR, vec![]), (ENV_USERVAR, vec![])];
+        }
+
+        let mut reply = vec![ENV_IS];
+        for (kind, name) in requests {
+            let mut found = false;
+            for var in self.vars.iter().filter(|v| v.kind() == kind) {
+                if name.is_empty() || name == var.name.as_bytes() {
+                    reply.push(kind);
+                    put_env_escaped(var.name.as_bytes(), &mut reply);
+                    reply.push(ENV_VALUE);
+                    put_env_escaped(var.value.as_bytes(), &mut reply);
+                    found = true;
+                }
+            }
+            // An unknown variable is reported without a value.
+            if !found && !name.is_empty() {
+                reply.push(kind);
+                put_env_escaped(&name, &mut reply);
+            }
+        }
+        Some(reply)
+    }
+}
+
+// Copy `data` into `dst`, escaping the NEW-ENVIRON type codes with `ESC`.
+fn put_env_escaped(data: &[u8], dst: &mut Vec<u8>) {
+    for &byte in data {
+        if matches!(byte, ENV_VAR | ENV_VALUE | ENV_ESC | ENV_USERVAR) {
+            dst.push(ENV_ESC);
+        }
+        dst.push(byte);
+    }
+}
+
+/// Agrees on a character set through CHARSET, from the configured ones in order of preference.
+///
+/// We offer the option and send `REQUEST` with our list once the server enabled it, a
+/// `REQUEST` from the server is answered with the first of our character sets it lists.
+/// The one agreed on is stored in `negotiated`, translation tables are refused.
+pub(crate) struct Charset {
+    charsets: Vec<(String, EncodingRef)>,
+    negotiated: Negotiated,
+    // Our `REQUEST` is waiting for an answer.
+    requested: bool,
+}
+
+impl Charset {
+    pub(crate) fn new(charsets: Vec<(String, EncodingRef)>, negotiated: Negotiated) -> Self {
+        Charset {
+            charsets,
+            negotiated,
+            requested: false,
+        }
+    }
+
+    // Our preferred character set among `names`, with the name as spelled in `names`.
+    fn choose<'a>(&self, names: &[&'a [u8]]) -> Option<(&'a [u8], EncodingRef)> {
+        self.charsets.iter().find_map(|(_, ours)| {
+            names.iter().find_map(|&name| {
+                let encoding = std::str::from_utf8(name).ok().and_then(by_name)?;
+                (encoding.name() == ours.name()).then_some((name, *ours))
+            })
+        })
+    }
+
+    fn agree(&mut self, name: &[u8], encoding: EncodingRef) {
+        if let Ok(mut negotiated) = self.negotiated.lock() {
+            *negotiated = Some((String::from_utf8_lossy(name).into_owned(), encoding));
+        }
+    }
+}
+
+impl fmt::Debug for Charset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.charsets.iter().map(|(name, _)| name).collect();
+        f.debug_struct("Charset")
+            .field("charsets", &names)
+            .field("requested", &self.requested)
+            .finish()
+    }
+}
+
+impl OptionHandler for Charset {
+    fn option(&self) -> u8 {
+        CHARSET
+    }
+
+    fn accept_local(&mut self) -> bool {
+        !self.charsets.is_empty()
+    }
+
+    fn accept_remote(&mut self) -> bool {
+        !self.charsets.is_empty()
+    }
+
+    fn offer_local(&self) -> bool {
+        !self.charsets.is_empty()
+    }
+
+    fn local_changed(&mut self, enabled: bool) -> Option<Vec<u8>> {
+        self.requested = enabled;
+        if !enabled {
+            return None;
+        }
+        let mut request = vec![CHARSET_REQUEST];
+        for (name, _) in self.charsets.iter() {
+            request.push(b';');
+            request.extend_from_slice(name.as_bytes());
+        }
+        Some(request)
+    }
+
+    fn subnegotiation(&mut self, payload: &[u8]) -> Option<Vec<u8>> {
+        let (&cmd, data) = payload.split_first()?;
+        match cmd {
+            CHARSET_REQUEST => {
+                // The server's request wins over ours (RFC 2066).
+                self.requested = false;
+                // A translation table version may come first, we never send one.
+                let data = match data.strip_prefix(b"[TTABLE ]") {
+                    Some(rest) => rest.get(1..).unwrap_or_default(),
+                    None => data,
+                };
+                // Every request is answered, a malformed one is rejected.
+                let chosen = data.split_first().and_then(|(&sep, list)| {
+                    let names: Vec<&[u8]> = list.split(|&b| b == sep).collect();
+                    self.choose(&names)
+                });
+                match chosen {
+                    Some((name, encoding)) => {
+                        self.agree(name, encoding);
+                        let mut reply = vec![CHARSET_ACCEPTED];
+                        reply.extend_from_slice(name);
+                        Some(reply)
+                    }
+                    None => Some(vec![CHARSET_REJECTED]),
+                }
+            }
+            CHARSET_ACCEPTED if self.requested => {
+                self.requested = false;
+                if let Some((name, encoding)) = self.choose(&[data]) {
+                    self.agree(name, encoding);
+                }
+                None
+            }
+            CHARSET_REJECTED => {
+                self.requested = false;
+                None
+            }
+            CHARSET_TTABLE_IS => Some(vec![CHARSET_TTABLE_REJECTED]),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::charset::{all::UTF_8, CP437};
+
+    fn terminal_types(handler: &mut TerminalType, n: usize) -> Vec<Option<Vec<u8>>> {
+        (0..n)
+            .map(|_| handler.subnegotiation(&[TTYPE_SEND]))
+            .collect()
+    }
+
+    #[test]
+    fn terminal_types_cycle() {
+        let mut handler = TerminalType::new(vec!["XTERM".to_string(), "VT100".to_string()]);
+        let is = |name: &[u8]| Some([&[TTYPE_IS], name].concat());
+        assert_eq!(
+            terminal_types(&mut handler, 5),
+            vec![
+                is(b"XTERM"),
+                is(b"VT100"),
+                is(b"VT100"),
+                is(b"XTERM"),
+                is(b"VT100")
+            ]
+        );
+
+        let mut handler = TerminalType::new(vec!["ANSI".to_string()]);
+        assert_eq!(terminal_types(&mut handler, 3), vec![is(b"ANSI"); 3]);
+    }
+
+    fn environ() -> NewEnviron {
+        NewEnviron::new(vec![
+            EnvVar::var("USER".to_string(), "admin".to_string()),
+            EnvVar::user_var("TOKEN".to_string(), "s\x01\x03".to_string()),
+        ])
+    }
+
+    #[test]
+    fn environ_empty_send_is_every_variable() {
+        assert_eq!(
+            environ().subnegotiation(&[ENV_SEND]),
+            Some(b"\x00\x00USER\x01admin\x03TOKEN\x01s\x02\x01\x02\x03".to_vec())
+        );
+    }
+
+    #[test]
+    fn environ_named_variables() {
+        // `VAR USER` and `USERVAR USER`: the name only exists as a VAR.
+        assert_eq!(
+            environ().subnegotiation(b"\x01\x00USER\x03USER"),
+            Some(b"\x00\x00USER\x01admin\x03USER".to_vec())
+        );
+        // Every USERVAR, and an unknown VAR reported without a value.
+        assert_eq!(
+            environ().subnegotiation(b"\x01\x03\x00HOME"),
+            Some(b"\x00\x03TOKEN\x01s\x02\x01\x02\x03\x00HOME".to_vec())
+        );
+    }
+
+    #[test]
+    fn environ_escaped_name() {
+        let mut handler = NewEnviron::new(vec![EnvVar::var("A\x02B".to_string(), "v".to_string())]);
+        assert_eq!(
+            handler.subnegotiation(b"\x01\x00A\x02\x02B"),
+            Some(b"\x00\x00A\x02\x02B\x01v".to_vec())
+        );
+        // Only SEND is answered.
+        assert_eq!(handler.subnegotiation(b"\x00"), None);
+    }
+
+    #[test]
+    fn environ_debug_hides_values() {
+        let debug = format!("{:?}", environ());
+        assert!(debug.contains("USER") && debug.contains("TOKEN"));
+        assert!(!debug.contains("admin"));
+    }
+
+    fn charset() -> (Charset, Negotiated) {
+        let negotiated = Negotiated::default();
+        let charsets: Vec<(String, EncodingRef)> =
+            vec![("UTF-8".to_string(), UTF_8), ("IBM437".to_string(), CP437)];
+        (Charset::new(charsets, negotiated.clone()), negotiated)
+    }
+
+    fn negotiated_name(negotiated: &Negotiated) -> Option<String> {
+        negotiated
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(name, _)| name.clone())
+    }
+
+    #[test]
+    fn charset_request_picks_our_preferred() {
+        let (mut handler, negotiated) = charset();
+        assert_eq!(
+            handler.subnegotiation(b"\x01;cp437;utf-8"),
+            Some(b"\x02utf-8".to_vec())
+        );
+        assert_eq!(negotiated_name(&negotiated).as_deref(), Some("utf-8"));
+
+        // Names are matched through their encoding, with any separator.
+        let (mut handler, _) = charset();
+        assert_eq!(
+            handler.subnegotiation(b"\x01 ISO-8859-1 437"),
+            Some(b"\x02437".to_vec())
+        );
+    }
+
+    #[test]
+    fn charset_request_skips_translation_table_version() {
+        let (mut handler, _) = charset();
+        assert_eq!(
+            handler.subnegotiation(b"\x01[TTABLE ]\x01;UTF-8"),
+            Some(b"\x02UTF-8".to_vec())
+        );
+    }
+
+    #[test]
+    fn charset_request_rejected() {
+        let (mut handler, negotiated) = charset();
+        assert_eq!(handler.subnegotiation(b"\x01;KOI8-R"), Some(vec![3]));
+        // Malformed requests get an answer too.
+        assert_eq!(handler.subnegotiation(b"\x01"), Some(vec![3]));
+        assert_eq!(handler.subnegotiation(b"\x01[TTABLE ]"), Some(vec![3]));
+        assert_eq!(negotiated_name(&negotiated), None);
+    }
+
+    #[test]
+    fn charset_answer_to_our_request() {
+        let (mut handler, negotiated) = charset();
+        assert_eq!(
+            handler.local_changed(true),
+            Some(b"\x01;UTF-8;IBM437".to_vec())
+        );
+        assert_eq!(handler.subnegotiation(b"\x02IBM437"), None);
+        assert_eq!(negotiated_name(&negotiated).as_deref(), Some("IBM437"));
+
+        // An answer we are not waiting for is ignored.
+        let (mut handler, negotiated) = charset();
+        assert_eq!(handler.subnegotiation(b"\x02UTF-8"), None);
+        assert_eq!(negotiated_name(&negotiated), None);
+
+        let (mut handler, negotiated) = charset();
+        handler.local_changed(true);
+        assert_eq!(handler.subnegotiation(b"\x03"), None);
+        assert_eq!(negotiated_name(&negotiated), None);
+        assert_eq!(handler.subnegotiation(b"\x02UTF-8"), None);
+        assert_eq!(negotiated_name(&negotiated), None);
+    }
+}
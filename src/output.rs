@@ -0,0 +1,293 @@
+//! Command output.
+use tokio::time::{Duration, Instant};
+
+use crate::ansi;
+use crate::codec::Item;
+use crate::prompt::{Pagers, Prompts};
+
+/// The output of `Telnet::execute_detailed`, with what it takes to debug it.
+#[derive(Debug, Clone)]
+pub struct CommandOutput {
+    /// The output as `execute` returns it.
+    pub output: String,
+    /// The prompt that ended the command, without escape sequences.
+    pub prompt: String,
+    /// The data received during the command, echo, escape sequences and prompt included.
+    /// Telnet commands are left out, they are in `events`, and `IAC IAC` is unescaped.
+    pub raw: Vec<u8>,
+    /// Time from sending the command to the first byte of output, `None` if nothing came.
+    pub first_byte: Option<Duration>,
+    /// Time from sending the command to the prompt.
+    pub duration: Duration,
+    /// Option negotiation and commands received during the command, in order.
+    pub events: Vec<Item>,
+}
+
+/// What is received while a command runs, recorded by `Telnet::execute_detailed`.
+#[derive(Debug, Default)]
+pub(crate) struct Transcript {
+    pub(crate) raw: Vec<u8>,
+    pub(crate) first_byte: Option<Instant>,
+    pub(crate) events: Vec<Item>,
+}
+
+impl Transcript {
+    pub(crate) fn data(&mut self, data: &[u8]) {
+        self.first_byte.get_or_insert_with(Instant::now);
+        self.raw.extend_from_slice(data);
+    }
+}
+
+/// What to do with a piece of command output.
+pub(crate) enum Step {
+    /// Nothing to hand out yet.
+    Skip,
+    /// A complete line of output, as received.
+    Line(Vec<u8>),
+    /// The prompt showed up, the command is done. Holds the prompt, stripped.
+    Prompt(Vec<u8>),
+    /// A pager marker showed up, send the response to get the next page.
+    Pager(Vec<u8>),
+}
+
+/// Splits the output of a command into lines, dropping the echo of the command and the prompt.
+pub(crate) struct OutputFilter {
+    command: String,
+    echo: bool,
+    line_feed_cnt: isize,
+    real_output: bool,
+    incomplete_line: Vec<u8>,
+    // Blanks left to drop while the server erases the last pager marker.
+    erase: Option<usize>,
+}
+
+impl OutputFilter {
+    /// Filter the output of `command`, `echo` tells whether the server enabled ECHO.
+    pub(crate) fn new(command: &str, echo: bool) -> Self {
+        OutputFilter {
+            command: command.to_string(),
+            echo,
+            line_feed_cnt: command.lines().count() as isize,
+            real_output: false,
+            incomplete_line: vec![],
+            erase: None,
+        }
+    }
+
+    /// Keep everything up to the prompt, echo included.
+    pub(crate) fn unfiltered() -> Self {
+        OutputFilter {
+            command: String::new(),
+            echo: false,
+            line_feed_cnt: 0,
+            real_output: true,
+            incomplete_line: vec![],
+            erase: None,
+        }
+    }
+
+    /// Feed the next piece of output as received, prompts and echo are looked for once
+    /// escape sequences are stripped.
+    pub(crate) fn feed(&mut self, mut line: Vec<u8>, prompts: &Prompts, pagers: &Pagers) -> Step {
+        if let Some(spaces) = self.erase {
+            let (len, spaces) = erased(&line, spaces);
+            line.drain(..len);
+            self.erase = spaces;
+            if line.is_empty() {
+                return Step::Skip;
+            }
+        }
+        if !self.real_output {
+            return self.feed_echo(line, prompts);
+        }
+        let text = ansi::strip(&line);
+        // ignore prompt line
+        if let Some(start) = prompts.find(&text) {
+            return Step::Prompt(text[start..].to_vec());
+        }
+
+        if !line.ends_with(&[10]) || !self.incomplete_line.is_empty() {
+            self.incomplete_line.append(&mut line);
+        } else {
+            return Step::Line(line);
+        }
+        // ignore command line
+        let text = ansi::strip(&self.incomplete_line);
+        if let Some(start) = prompts.find(&text) {
+            return Step::Prompt(text[start..].to_vec());
+        }
+        if let Some((start, response)) = pagers.find(&text) {
+            self.erase = Some(text.len() - start);
+            // The output before the marker is all that is left to show.
+            let end = ansi::raw_offset(&self.incomplete_line, start);
+            self.incomplete_line.truncate(end);
+            return Step::Pager(response.to_vec());
+        }
+        if self.incomplete_line.ends_with(&[10]) {
+            return Step::Line(std::mem::take(&mut self.incomplete_line));
+        }
+        Step::Skip
+    }
+
+    // Before the output starts: drop the echo of the command, a line at a time as it may
+    // arrive in pieces.
+    fn feed_echo(&mut self, mut line: Vec<u8>, prompts: &Prompts) -> Step {
+        self.incomplete_line.append(&mut line);
+        let text = ansi::strip(&self.incomplete_line);
+        // ignore prompt line
+        if let Some(start) = prompts.find(&text) {
+            self.incomplete_line.clear();
+            return Step::Prompt(text[start..].to_vec());
+        }
+        if !text.ends_with(&[10]) {
+            return Step::Skip;
+        }
+        // Without remote ECHO, the echo may be missing.
+        if !self.echo && !is_echo(&text, &self.command, prompts) {
+            self.line_feed_cnt = 0;
+            self.real_output = true;
+            return Step::Line(std::mem::take(&mut self.incomplete_line));
+        }
+        self.incomplete_line.clear();
+        self.line_feed_cnt -= 1;
+        if self.line_feed_cnt <= 0 {
+            self.real_output = true;
+        }
+        Step::Skip
+    }
+}
+
+// Whether `line` repeats one of the lines of `command`, alone or after a prompt. A blank line
+// never does.
+fn is_echo(line: &[u8], command: &str, prompts: &Prompts) -> bool {
+    let end = line
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(0, |i| i + 1);
+    end > 0
+        && command.lines().any(|l| {
+            let l = l.trim_end();
+            if l.is_empty() || !line[..end].ends_with(l.as_bytes()) {
+                return false;
+            }
+            let before = &line[..end - l.len()];
+            before.iter().all(u8::is_ascii_whitespace) || prompts.matches(before)
+        })
+}
+
+// How many bytes at the start of `line` erase a pager marker: backspaces, carriage returns,
+// cursor moves and at most `spaces` blanks. Also returns the blanks still allowed when the
+// whole line was consumed, as the erasing may go on in the next piece.
+fn erased(line: &[u8], mut spaces: usize) -> (usize, Option<usize>) {
+    let mut i = 0;
+    while i < line.len() {
+        match line[i] {
+            0 | 8 | 13 => i += 1,
+            b' ' if spaces > 0 => {
+                spaces -= 1;
+                i += 1;
+            }
+            // `ESC [ n D` (cursor back) and `ESC [ n K` (erase line), ESC may be gone already.
+            27 | b'[' => {
+                let start = if line[i] == 27 { i + 1 } else { i };
+                if line.get(start) != Some(&b'[') {
+                    return (i, None);
+                }
+                let digits = line[start + 1..]
+                    .iter()
+                    .take_while(|b| b.is_ascii_digit())
+                    .count();
+                match line.get(start + 1 + digits) {
+                    Some(b'D') | Some(b'K') => i = start + 2 + digits,
+                    _ => return (i, None),
+                }
+            }
+            _ => return (i, None),
+        }
+    }
+    (i, Some(spaces))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(filter: &mut OutputFilter, pieces: &[&[u8]]) -> Vec<Vec<u8>> {
+        let prompts = Prompts::new(vec!["$ ".to_string()], &[]).unwrap();
+        let pagers = Pagers::new(vec![("--More--".to_string(), " ".to_string())], vec![]).unwrap();
+        let mut lines = vec![];
+        for piece in pieces {
+            match filter.feed(piece.to_vec(), &prompts, &pagers) {
+                Step::Line(line) => lines.push(line),
+                Step::Prompt(_) => break,
+                _ => {}
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn output_ending_the_command_is_not_echo() {
+        let mut filter = OutputFilter::new("echo hi\n", false);
+        assert_eq!(
+            run(&mut filter, &[b"hi\r\n", b"$ "]),
+            vec![b"hi\r\n".to_vec()]
+        );
+    }
+
+    #[test]
+    fn blank_first_line_is_not_echo() {
+        let mut filter = OutputFilter::new("echo\n", false);
+        assert_eq!(run(&mut filter, &[b"\r\n", b"$ "]), vec![b"\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn echo_is_dropped() {
+        let mut filter = OutputFilter::new("echo hi\n", false);
+        let lines = run(&mut filter, &[b"$ echo hi\r\n", b"hi\r\n", b"$ "]);
+        assert_eq!(lines, vec![b"hi\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn output_before_pager_is_kept_as_received() {
+        let mut filter = OutputFilter::unfiltered();
+        let lines = run(
+            &mut filter,
+            &[
+                b"\x1b[1mone\x1b[0m\r--More--",
+                b"\x08\x08\x08\x08\x08\x08\x08\x08",
+                b"\n",
+                b"$ ",
+            ],
+        );
+        assert_eq!(lines, vec![b"\x1b[1mone\x1b[0m\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn prompt_is_sliced_off_its_line() {
+        let prompts = Prompts::new(vec!["$ ".to_string()], &[]).unwrap();
+        let mut filter = OutputFilter::unfiltered();
+        match filter.feed(
+            b"\x1b[32muser@host\x1b[0m:~$ ".to_vec(),
+            &prompts,
+            &Pagers::default(),
+        ) {
+            Step::Prompt(prompt) => assert_eq!(prompt, b"$ "),
+            _ => panic!("no prompt"),
+        }
+    }
+
+    #[test]
+    fn echo_split_across_pieces_is_dropped() {
+        let mut filter = OutputFilter::new("echo hi\n", false);
+        let lines = run(&mut filter, &[b"echo h", b"i\r\n", b"hi\r\n", b"$ "]);
+        assert_eq!(lines, vec![b"hi\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn output_ending_with_the_command_is_not_echo() {
+        let mut filter = OutputFilter::new("ls\n", false);
+        let lines = run(&mut filter, &[b"bin  tools\r\n", b"etc\r\n", b"$ "]);
+        assert_eq!(lines, vec![b"bin  tools\r\n".to_vec(), b"etc\r\n".to_vec()]);
+    }
+}
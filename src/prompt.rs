@@ -0,0 +1,81 @@
+use regex::bytes::Regex;
+
+use crate::error::TelnetError;
+
+/// Text expected at the end of a line.
+#[derive(Debug)]
+enum Ending {
+    Literal(String),
+    Regex(Regex),
+}
+
+impl Ending {
+    fn regex(pattern: &str) -> Result<Self, TelnetError> {
+        Ok(Ending::Regex(Regex::new(&format!("(?:{})$", pattern))?))
+    }
+
+    // Where the match at the end of `line` starts.
+    fn find(&self, line: &[u8]) -> Option<usize> {
+        match self {
+            Ending::Literal(literal) => line
+                .ends_with(literal.as_bytes())
+                .then(|| line.len() - literal.len()),
+            Ending::Regex(re) => re.find(line).map(|m| m.start()),
+        }
+    }
+}
+
+/// The shell prompts, matched at the end of a line.
+#[derive(Debug, Default)]
+pub(crate) struct Prompts {
+    endings: Vec<Ending>,
+}
+
+impl Prompts {
+    pub(crate) fn new(literals: Vec<String>, patterns: &[String]) -> Result<Self, TelnetError> {
+        let mut endings: Vec<Ending> = literals.into_iter().map(Ending::Literal).collect();
+        for pattern in patterns {
+            endings.push(Ending::regex(pattern)?);
+        }
+        Ok(Prompts { endings })
+    }
+
+    /// Where the prompt at the end of `line` starts, if there is one.
+    pub(crate) fn find(&self, line: &[u8]) -> Option<usize> {
+        self.endings.iter().find_map(|e| e.find(line))
+    }
+
+    /// Whether `line` ends with a prompt.
+    pub(crate) fn matches(&self, line: &[u8]) -> bool {
+        self.find(line).is_some()
+    }
+}
+
+/// Pager markers such as `--More--`, each with the response asking for the next page.
+#[derive(Debug, Default)]
+pub(crate) struct Pagers {
+    pagers: Vec<(Ending, Vec<u8>)>,
+}
+
+impl Pagers {
+    pub(crate) fn new(
+        literals: Vec<(String, String)>,
+        patterns: Vec<(String, String)>,
+    ) -> Result<Self, TelnetError> {
+        let mut pagers: Vec<(Ending, Vec<u8>)> = literals
+            .into_iter()
+            .map(|(marker, response)| (Ending::Literal(marker), response.into_bytes()))
+            .collect();
+        for (pattern, response) in patterns {
+            pagers.push((Ending::regex(&pattern)?, response.into_bytes()));
+        }
+        Ok(Pagers { pagers })
+    }
+
+    /// Where the pager marker at the end of `line` starts, and the response to send.
+    pub(crate) fn find(&self, line: &[u8]) -> Option<(usize, &[u8])> {
+        self.pagers
+            .iter()
+            .find_map(|(e, response)| e.find(line).map(|start| (start, response.as_slice())))
+    }
+}
@@ -0,0 +1,509 @@
+//! A VT100/xterm subset terminal emulator, for servers that redraw the screen.
+use crate::ansi::{Action, Parser};
+use crate::charset::Charsets;
+
+/// One character cell of the screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub ch: char,
+    pub bold: bool,
+    pub underline: bool,
+    /// Reverse video, menus often highlight the selected entry this way.
+    pub inverse: bool,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Cell {
+            ch: ' ',
+            bold: false,
+            underline: false,
+            inverse: false,
+        }
+    }
+}
+
+/// The screen as drawn by the server output read so far.
+///
+/// Cursor movement, erasing, scrolling regions, insert and delete, and the bold, underline
+/// and inverse attributes are supported. Colors are ignored, output is decoded with the
+/// first encoding set with `TelnetBuilder::encodings` and every character takes one cell.
+#[derive(Debug)]
+pub struct Screen {
+    cols: usize,
+    rows: usize,
+    grid: Vec<Vec<Cell>>,
+    row: usize,
+    col: usize,
+    saved: (usize, usize, Cell),
+    // Attributes of the characters printed next.
+    pen: Cell,
+    // Rows `top..=bottom` scroll, set with `ESC [ r`.
+    top: usize,
+    bottom: usize,
+    // The last column was just written, the next character goes to the next line.
+    wrap_pending: bool,
+    parser: Parser,
+    charsets: Charsets,
+    // Bytes of a multibyte character split across reads.
+    undecoded: Vec<u8>,
+}
+
+impl Screen {
+    pub(crate) fn new(cols: u16, rows: u16, charsets: Charsets) -> Self {
+        let cols = usize::from(cols.max(1));
+        let rows = usize::from(rows.max(1));
+        Screen {
+            cols,
+            rows,
+            grid: vec![vec![Cell::default(); cols]; rows],
+            row: 0,
+            col: 0,
+            saved: (0, 0, Cell::default()),
+            pen: Cell::default(),
+            top: 0,
+            bottom: rows - 1,
+            wrap_pending: false,
+            parser: Parser::default(),
+            charsets,
+            undecoded: vec![],
+        }
+    }
+
+    /// The cells, row by row.
+    pub fn cells(&self) -> &[Vec<Cell>] {
+        &self.grid
+    }
+
+    /// The cursor position as `(row, column)`, counted from zero.
+    pub fn cursor(&self) -> (u16, u16) {
+        (self.row as u16, self.col as u16)
+    }
+
+    /// The text of the screen, one line per row without trailing spaces.
+    pub fn contents(&self) -> String {
+        self.grid
+            .iter()
+            .map(|row| {
+                let line: String = row.iter().map(|cell| cell.ch).collect();
+                line.trim_end_matches(' ').to_string()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Draw `data` as received from the server.
+    pub(crate) fn feed(&mut self, data: &[u8]) {
+        for &byte in data {
+            match self.parser.advance(byte) {
+                Some(Action::Print(byte)) => self.print(byte),
+                Some(Action::Control(byte)) => self.control(byte),
+                Some(Action::Csi {
+                    private,
+                    params,
+                    final_byte,
+                }) => self.csi(private, &params, final_byte),
+                Some(Action::Esc {
+                    intermediates,
+                    final_byte,
+                }) => {
+                    if intermediates.is_empty() {
+                        self.esc(final_byte)
+                    }
+                }
+                Some(Action::Osc) | None => {}
+            }
+        }
+    }
+
+    /// Change the size, keeping the top left part of the screen.
+    pub(crate) fn resize(&mut self, cols: u16, rows: u16) {
+        self.cols = usize::from(cols.max(1));
+        self.rows = usize::from(rows.max(1));
+        self.grid.resize(self.rows, vec![]);
+        for row in self.grid.iter_mut() {
+            row.resize(self.cols, Cell::default());
+        }
+        self.top = 0;
+        self.bottom = self.rows - 1;
+        self.goto(self.row, self.col);
+    }
+
+    fn print(&mut self, byte: u8) {
+        if byte < 0x80 && self.undecoded.is_empty() {
+            return self.put(char::from(byte));
+        }
+        let mut bytes = std::mem::take(&mut self.undecoded);
+        bytes.push(byte);
+        loop {
+            let mut text = String::new();
+            let mut decoder = self.charsets.primary().raw_decoder();
+            let (processed, error) = decoder.raw_feed(&bytes, &mut text);
+            text.chars().for_each(|ch| self.put(ch));
+            match error {
+                // Replace the invalid sequence and go on with the bytes after it.
+                Some(error) => {
+                    self.put(char::REPLACEMENT_CHARACTER);
+                    let upto = usize::try_from(error.upto).unwrap_or(0);
+                    bytes.drain(..upto.max(processed + 1).min(bytes.len()));
+                }
+                // Keep the start of a character that is not complete yet.
+                None => {
+                    bytes.drain(..processed);
+                    self.undecoded = bytes;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn put(&mut self, ch: char) {
+        if self.wrap_pending {
+            self.col = 0;
+            self.line_feed();
+        }
+        self.grid[self.row][self.col] = Cell { ch, ..self.pen };
+        if self.col + 1 == self.cols {
+            self.wrap_pending = true;
+        } else {
+            self.col += 1;
+        }
+    }
+
+    fn control(&mut self, byte: u8) {
+        match byte {
+            // BS
+            0x08 => self.goto(self.row, self.col.saturating_sub(1)),
+            // HT
+            b'\t' => self.goto(self.row, (self.col / 8 + 1) * 8),
+            // LF, VT and FF
+            0x0a..=0x0c => {
+                self.wrap_pending = false;
+                self.line_feed();
+            }
+            b'\r' => self.goto(self.row, 0),
+            _ => {}
+        }
+    }
+
+    fn esc(&mut self, final_byte: u8) {
+        match final_byte {
+            b'7' => self.saved = (self.row, self.col, self.pen),
+            b'8' => {
+                let (row, col, pen) = self.saved;
+                self.pen = pen;
+                self.goto(row, col);
+            }
+            b'D' => {
+                self.wrap_pending = false;
+                self.line_feed();
+            }
+            b'E' => {
+                self.goto(self.row, 0);
+                self.line_feed();
+            }
+            b'M' => {
+                self.wrap_pending = false;
+                if self.row == self.top {
+                    self.scroll_down(1);
+                } else {
+                    self.row = self.row.saturating_sub(1);
+                }
+            }
+            b'c' => *self = Screen::new(self.cols as u16, self.rows as u16, self.charsets.clone()),
+            _ => {}
+        }
+    }
+
+    fn csi(&mut self, private: Option<u8>, params: &[u16], final_byte: u8) {
+        // Counts and positions treat 0 and a missing parameter alike.
+        let arg = |i: usize, default: usize| match params.get(i) {
+            Some(&n) if n > 0 => usize::from(n),
+            _ => default,
+        };
+        let mode = params.first().copied().unwrap_or(0);
+
+        if private == Some(b'?') {
+            if let b'h' | b'l' = final_byte {
+                for &param in params {
+                    self.private_mode(param, final_byte == b'h');
+                }
+            }
+            return;
+        }
+        if private.is_some() {
+            return;
+        }
+
+        let (row, col) = (self.row, self.col);
+        match final_byte {
+            b'A' => self.goto(row.saturating_sub(arg(0, 1)), col),
+            b'B' | b'e' => self.goto(row + arg(0, 1), col),
+            b'C' | b'a' => self.goto(row, col + arg(0, 1)),
+            b'D' => self.goto(row, col.saturating_sub(arg(0, 1))),
+            b'E' => self.goto(row + arg(0, 1), 0),
+            b'F' => self.goto(row.saturating_sub(arg(0, 1)), 0),
+            b'G' | b'`' => self.goto(row, arg(0, 1) - 1),
+            b'd' => self.goto(arg(0, 1) - 1, col),
+            b'H' | b'f' => self.goto(arg(0, 1) - 1, arg(1, 1) - 1),
+            b'J' => match mode {
+                0 => {
+                    self.erase(row, col, self.cols);
+                    for r in row + 1..self.rows {
+                        self.erase(r, 0, self.cols);
+                    }
+                }
+                1 => {
+                    for r in 0..row {
+                        self.erase(r, 0, self.cols);
+                    }
+                    self.erase(row, 0, col + 1);
+                }
+                _ => self.clear(),
+            },
+            b'K' => match mode {
+                0 => self.erase(row, col, self.cols),
+                1 => self.erase(row, 0, col + 1),
+                _ => self.erase(row, 0, self.cols),
+            },
+            b'X' => self.erase(row, col, col + arg(0, 1)),
+            b'@' => {
+                let line = &mut self.grid[row];
+                for _ in 0..arg(0, 1).min(self.cols - col) {
+                    line.insert(col, Cell::default());
+                }
+                line.truncate(self.cols);
+            }
+            b'P' => {
+                let line = &mut self.grid[row];
+                for _ in 0..arg(0, 1).min(self.cols - col) {
+                    line.remove(col);
+                    line.push(Cell::default());
+                }
+            }
+            b'L' | b'M' if (self.top..=self.bottom).contains(&row) => {
+                for _ in 0..arg(0, 1).min(self.bottom - row + 1) {
+                    if final_byte == b'L' {
+                        self.grid.remove(self.bottom);
+                        self.grid.insert(row, vec![Cell::default(); self.cols]);
+                    } else {
+                        self.grid.remove(row);
+                        self.grid
+                            .insert(self.bottom, vec![Cell::default(); self.cols]);
+                    }
+                }
+                self.goto(row, 0);
+            }
+            b'S' => self.scroll_up(arg(0, 1)),
+            b'T' => self.scroll_down(arg(0, 1)),
+            b'r' => {
+                let (top, bottom) = (arg(0, 1) - 1, arg(1, self.rows) - 1);
+                if top < bottom && bottom < self.rows {
+                    self.top = top;
+                    self.bottom = bottom;
+                    self.goto(0, 0);
+                }
+            }
+            b'm' => self.sgr(params),
+            b's' => self.saved = (row, col, self.pen),
+            b'u' => {
+                let (row, col, _) = self.saved;
+                self.goto(row, col);
+            }
+            _ => {}
+        }
+    }
+
+    fn private_mode(&mut self, mode: u16, set: bool) {
+        match mode {
+            // The alternate screen, there is only one buffer so it is cleared instead.
+            1049 => {
+                if set {
+                    self.saved = (self.row, self.col, self.pen);
+                    self.clear();
+                } else {
+                    self.clear();
+                    let (row, col, pen) = self.saved;
+                    self.pen = pen;
+                    self.goto(row, col);
+                }
+            }
+            47 | 1047 => self.clear(),
+            _ => {}
+        }
+    }
+
+    fn sgr(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            self.pen = Cell::default();
+            return;
+        }
+        let mut params = params.iter();
+        while let Some(&param) = params.next() {
+            match param {
+                0 => self.pen = Cell::default(),
+                1 => self.pen.bold = true,
+                4 => self.pen.underline = true,
+                7 => self.pen.inverse = true,
+                22 => self.pen.bold = false,
+                24 => self.pen.underline = false,
+                27 => self.pen.inverse = false,
+                // Extended colors, skip their arguments so they are not taken for attributes.
+                38 | 48 | 58 => match params.next() {
+                    Some(5) => {
+                        params.next();
+                    }
+                    Some(2) => {
+                        params.nth(2);
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+    }
+
+    // Move the cursor, staying on the screen.
+    fn goto(&mut self, row: usize, col: usize) {
+        self.row = row.min(self.rows - 1);
+        self.col = col.min(self.cols - 1);
+        self.wrap_pending = false;
+    }
+
+    fn line_feed(&mut self) {
+        if self.row == self.bottom {
+            self.scroll_up(1);
+        } else if self.row + 1 < self.rows {
+            self.row += 1;
+        }
+    }
+
+    // Scroll the scrolling region up by `n` lines.
+    fn scroll_up(&mut self, n: usize) {
+        for _ in 0..n.min(self.bottom - self.top + 1) {
+            self.grid.remove(self.top);
+            self.grid
+                .insert(self.bottom, vec![Cell::default(); self.cols]);
+        }
+    }
+
+    // Scroll the scrolling region down by `n` lines.
+    fn scroll_down(&mut self, n: usize) {
+        for _ in 0..n.min(self.bottom - self.top + 1) {
+            self.grid.remove(self.bottom);
+            self.grid.insert(self.top, vec![Cell::default(); self.cols]);
+        }
+    }
+
+    // Blank the cells `start..end` of `row`.
+    fn erase(&mut self, row: usize, start: usize, end: usize) {
+        let end = end.min(self.cols);
+        if start < end {
+            self.grid[row][start..end].fill(Cell::default());
+        }
+    }
+
+    fn clear(&mut self) {
+        for row in self.grid.iter_mut() {
+            row.fill(Cell::default());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen(cols: u16, rows: u16) -> Screen {
+        Screen::new(cols, rows, Charsets::default())
+    }
+
+    #[test]
+    fn cursor_addressing() {
+        let mut s = screen(10, 3);
+        s.feed(b"\x1b[2;3Hhi");
+        assert_eq!(s.contents(), "\n  hi\n");
+        assert_eq!(s.cursor(), (1, 4));
+        s.feed(b"\x1b[H\x1b[5Cx\x1b[99;99Hz");
+        assert_eq!(s.contents(), "     x\n  hi\n         z");
+    }
+
+    #[test]
+    fn scroll_at_bottom_margin() {
+        let mut s = screen(5, 3);
+        s.feed(b"a\r\nb\r\nc\r\nd");
+        assert_eq!(s.contents(), "b\nc\nd");
+
+        // Only rows 2 and 3 scroll.
+        let mut s = screen(5, 3);
+        s.feed(b"top\x1b[2;3r\x1b[2;1Hb\r\nc\r\nd");
+        assert_eq!(s.contents(), "top\nc\nd");
+    }
+
+    #[test]
+    fn wrap_at_last_column() {
+        let mut s = screen(3, 2);
+        s.feed(b"abc");
+        // The cursor stays on the last column until the next character.
+        assert_eq!(s.cursor(), (0, 2));
+        s.feed(b"d");
+        assert_eq!(s.contents(), "abc\nd");
+    }
+
+    #[test]
+    fn insert_and_delete() {
+        let mut s = screen(6, 3);
+        s.feed(b"abcdef\x1b[1;2H\x1b[2@");
+        assert_eq!(s.contents(), "a  bcd\n\n");
+        s.feed(b"\x1b[3P");
+        assert_eq!(s.contents(), "acd\n\n");
+        s.feed(b"\x1b[2;1Hx\x1b[1;1H\x1b[L");
+        assert_eq!(s.contents(), "\nacd\nx");
+        s.feed(b"\x1b[M");
+        assert_eq!(s.contents(), "acd\nx\n");
+    }
+
+    #[test]
+    fn attributes() {
+        let mut s = screen(5, 1);
+        s.feed(b"\x1b[1;4mA\x1b[22;7mB\x1b[38;5;1mC\x1b[0mD");
+        let cells = &s.cells()[0];
+        assert!(cells[0].bold && cells[0].underline && !cells[0].inverse);
+        assert!(!cells[1].bold && cells[1].underline && cells[1].inverse);
+        // The color arguments are not taken for attributes.
+        assert_eq!(
+            cells[2],
+            Cell {
+                ch: 'C',
+                ..cells[1]
+            }
+        );
+        assert_eq!(
+            cells[3],
+            Cell {
+                ch: 'D',
+                ..Cell::default()
+            }
+        );
+    }
+
+    #[test]
+    fn character_split_across_feeds() {
+        let mut s = screen(5, 1);
+        s.feed(b"caf\xc3");
+        assert_eq!(s.contents(), "caf");
+        s.feed(b"\xa9!");
+        assert_eq!(s.contents(), "café!");
+    }
+
+    #[test]
+    fn resize_below_the_cursor() {
+        let mut s = screen(10, 5);
+        s.feed(b"ab\x1b[5;8Hx");
+        s.resize(4, 2);
+        assert_eq!(s.cursor(), (1, 3));
+        assert_eq!(s.contents(), "ab\n");
+        s.feed(b"y");
+        assert_eq!(s.contents(), "ab\n   y");
+    }
+}